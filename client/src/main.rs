@@ -1,17 +1,31 @@
+use clap::Parser;
 use cursive::traits::*;
 use cursive::views::{EditView, LinearLayout, TextView};
 use cursive::{CbSink, Cursive, CursiveExt, style::Palette, theme, view};
+use futures::io::WriteHalf;
+use futures::stream::BoxStream;
 use futures::{AsyncReadExt, StreamExt};
 use libp2p::swarm::{Stream, Swarm, SwarmEvent, dial_opts::DialOpts};
-use libp2p::{Multiaddr, PeerId, SwarmBuilder, request_response as reqres};
-use magicp2p::socket::*;
+use libp2p::{Multiaddr, PeerId, SwarmBuilder, identity::Keypair, request_response as reqres};
+use magicp2p::{keystore, socket::*};
 use scanf::sscanf;
-use std::{str, thread};
+use std::path::PathBuf;
+use std::thread;
 use tokio::sync::mpsc;
 use tokio::{runtime, select};
 
 const LISTEN_INTERFACE: &str = "/ip4/0.0.0.0/udp/0/quic-v1";
-const SERVER_INTERFACE: &str = "/ip4/0.0.0.0/udp/1234/quic-v1";
+const SERVER_INTERFACE: &str = "/ip4/127.0.0.1/udp/1234/quic-v1";
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Opt {
+    /// Keyfile for the client identity, created if it doesn't exist. Its
+    /// PeerId has to be in the daemon's authorized clients file, so unlike
+    /// the daemon a throwaway identity is never useful here.
+    #[arg(short, long, value_name = "path")]
+    identity: PathBuf,
+}
 
 /// Where the user is typing
 const INPUT_BOX: &str = "input_box";
@@ -21,12 +35,13 @@ const CHAT_DISPLAY: &str = "chat_display";
 enum SocketOpts {
     ServerLost(PeerId),
     ServerFound(PeerId),
+    Response(ResponseEvent),
 }
 
 /// Builds the cursive runtime and configures the UI layout and theme.
 ///
 /// `client_tx` will send messages generated by `INPUT_BOX` to be processed by the network
-fn setup_layout(client_tx: mpsc::UnboundedSender<String>) -> Cursive {
+fn setup_layout(client_tx: mpsc::UnboundedSender<String>, peer_id: PeerId) -> Cursive {
     let mut siv = Cursive::default();
     siv.set_window_title("magic_circle");
     siv.set_theme(theme::Theme {
@@ -36,7 +51,8 @@ fn setup_layout(client_tx: mpsc::UnboundedSender<String>) -> Cursive {
     });
 
     // Where all the text will be displayed
-    let chat_view = TextView::new("Welcome to magic_circle!\n")
+    let welcome = format!("Welcome to magic_circle!\nYou are <{}>\n", peer_id);
+    let chat_view = TextView::new(welcome)
         .with_name(CHAT_DISPLAY)
         .scrollable()
         .scroll_strategy(view::ScrollStrategy::StickToBottom);
@@ -100,24 +116,58 @@ fn command_parse(text: &str) -> Option<RequestEvent> {
     None
 }
 
-fn user_input_handle(
+/// Our end of the message stream and the channel plain text is sent to
+#[derive(Default)]
+struct Session {
+    writer: Option<WriteHalf<Stream>>,
+    frames: Option<BoxStream<'static, MessageFrame>>,
+    /// The last channel we joined
+    channel: Option<String>,
+}
+
+impl Session {
+    async fn next_frame(&mut self) -> Option<MessageFrame> {
+        match &mut self.frames {
+            Some(frames) => frames.next().await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+async fn user_input_handle(
     behaviour: &mut SocketBehaviour,
     text: String,
     ui_sink: &CbSink,
     server_id: Option<PeerId>,
+    session: &mut Session,
 ) {
     if let Some(req) = command_parse(&text) {
+        if let RequestType::JOIN = req.kind {
+            session.channel = Some(req.channel.clone());
+        }
         if let Some(peer_id) = server_id {
             behaviour.send_request(&peer_id, req);
         }
+    } else if !text.starts_with('/') {
+        // Plain text goes to the last channel we joined
+        match (&mut session.writer, &session.channel) {
+            (Some(writer), Some(channel)) => {
+                let frame = MessageFrame::new(channel.clone(), text.clone().into_bytes());
+                if let Err(e) = write_frame(writer, &frame).await {
+                    update_chat_display(ui_sink, format!("error: {}", e));
+                    session.writer = None;
+                }
+            }
+            _ => update_chat_display(ui_sink, "error: Join a channel first".to_string()),
+        }
     }
 
     // Sends message to update the UI next frame
-    update_chat_display(&ui_sink, text);
+    update_chat_display(ui_sink, text);
 }
 
-fn init_swarm(interface: Multiaddr) -> Swarm<SocketBehaviour> {
-    let mut swarm = SwarmBuilder::with_new_identity()
+fn init_swarm(keys: Keypair, interface: Multiaddr) -> Swarm<SocketBehaviour> {
+    let mut swarm = SwarmBuilder::with_existing_identity(keys)
         .with_tokio()
         .with_quic()
         .with_behaviour(|_| SocketBehaviour::new_client())
@@ -140,10 +190,10 @@ fn swarm_event_handle(event: SwarmEvent<SocketBehaviourEvent>) -> Option<SocketO
         SwarmEvent::ConnectionClosed { peer_id, .. } => Some(SocketOpts::ServerLost(peer_id)),
         // We don't need to handle the stream event see `stream_message_handle()`
         SwarmEvent::Behaviour(SocketBehaviourEvent::Socket(event)) => match event {
-            reqres::Event::Message { message, .. } => match message {
-                reqres::Message::Response { .. } => None,
-                _ => unreachable!(), // A request can never be sent to us; see `SocketBehaviour::new_client()`
-            },
+            reqres::Event::Message {
+                message: reqres::Message::Response { response, .. },
+                ..
+            } => Some(SocketOpts::Response(response)),
             reqres::Event::OutboundFailure { .. } => None,
             _ => unreachable!(), // A request can never be sent to us; see `SocketBehaviour::new_client()`
         },
@@ -151,34 +201,70 @@ fn swarm_event_handle(event: SwarmEvent<SocketBehaviourEvent>) -> Option<SocketO
     }
 }
 
-fn network_handle(event: SwarmEvent<SocketBehaviourEvent>, server_id: &mut Option<PeerId>) {
+fn network_handle(
+    swarm: &mut Swarm<SocketBehaviour>,
+    event: SwarmEvent<SocketBehaviourEvent>,
+    server_id: &mut Option<PeerId>,
+    ui_sink: &CbSink,
+) {
     if let Some(e) = swarm_event_handle(event) {
         match e {
-            SocketOpts::ServerFound(x) => *server_id = Some(x),
-            SocketOpts::ServerLost(_) => *server_id = None,
+            SocketOpts::ServerFound(x) => {
+                *server_id = Some(x);
+                // Ask for the message stream straight away
+                let req = RequestEvent {
+                    kind: RequestType::STRM,
+                    channel: String::new(),
+                    data: None,
+                };
+                swarm.behaviour_mut().send_request(&x, req);
+            }
+            SocketOpts::ServerLost(x) => {
+                if *server_id == Some(x) {
+                    *server_id = None;
+                }
+            }
+            SocketOpts::Response(response) => match response {
+                ResponseEvent::Ok | ResponseEvent::Published { .. } => {}
+                ResponseEvent::Channels(channels) => {
+                    update_chat_display(ui_sink, format!("channels: {}", channels.join(" ")))
+                }
+                ResponseEvent::Peers { count } => {
+                    update_chat_display(ui_sink, format!("peers: {}", count))
+                }
+                ResponseEvent::Err(e) => update_chat_display(ui_sink, format!("error: {}", e)),
+            },
         }
     }
 }
 
-async fn stream_message_handle(mut data: Stream, ui_sink: &CbSink) {
-    // Wow, some normal networking code
-    let mut buf: Vec<u8> = vec![];
-
-    // messages are so small don't read till end
-    let _ = data.read(&mut buf).await;
-
-    let message = str::from_utf8(&buf).unwrap();
+fn stream_message_handle(stream: Stream, session: &mut Session) {
+    let (reader, writer) = stream.split();
+    session.writer = Some(writer);
+    session.frames = Some(frames(reader));
+}
 
-    update_chat_display(ui_sink, message.to_string());
+fn frame_handle(frame: MessageFrame, ui_sink: &CbSink) {
+    let text = String::from_utf8_lossy(&frame.data);
+    let line = match frame.source {
+        Some(source) => format!("#{} <{}> {}", frame.topic, source, text),
+        None => format!("#{} {}", frame.topic, text),
+    };
+    update_chat_display(ui_sink, line);
 }
 
 /// Event loop the the networking code. It is expected for this to be on its own thread
 ///
 /// `client_rx` gets user input from `INPUT_BOX` while `ui_sink` is to send callbacks to
 /// the cursive runtime.
-async fn network_manager(mut client_rx: mpsc::UnboundedReceiver<String>, ui_sink: CbSink) {
-    let mut swarm: Swarm<SocketBehaviour> = init_swarm(SERVER_INTERFACE.parse().unwrap());
+async fn network_manager(
+    keys: Keypair,
+    mut client_rx: mpsc::UnboundedReceiver<String>,
+    ui_sink: CbSink,
+) {
+    let mut swarm: Swarm<SocketBehaviour> = init_swarm(keys, SERVER_INTERFACE.parse().unwrap());
     let mut server_id: Option<PeerId> = None;
+    let mut session = Session::default();
 
     // The stream that we will get all messages from
     let mut message_stream = swarm
@@ -189,19 +275,26 @@ async fn network_manager(mut client_rx: mpsc::UnboundedReceiver<String>, ui_sink
 
     loop {
         select! {
-            Some(text) = client_rx.recv() => user_input_handle(swarm.behaviour_mut(), text, &ui_sink, server_id),
-            event = swarm.select_next_some() => network_handle(event, &mut server_id),
-            Some((_, message)) = message_stream.next() => stream_message_handle(message, &ui_sink).await,
+            Some(text) = client_rx.recv() => user_input_handle(swarm.behaviour_mut(), text, &ui_sink, server_id, &mut session).await,
+            event = swarm.select_next_some() => network_handle(&mut swarm, event, &mut server_id, &ui_sink),
+            Some((_, stream)) = message_stream.next() => stream_message_handle(stream, &mut session),
+            frame = session.next_frame() => match frame {
+                Some(frame) => frame_handle(frame, &ui_sink),
+                None => session.frames = None,
+            },
 
         }
     }
 }
 
 fn main() {
+    let args = Opt::parse();
+    let keys = keystore::identity(Some(&args.identity)).expect("Could not load identity");
+
     // For messages sent from `INPUT_BOX`
     let (client_tx, client_rx) = mpsc::unbounded_channel::<String>();
 
-    let mut siv = setup_layout(client_tx);
+    let mut siv = setup_layout(client_tx, keys.public().to_peer_id());
 
     // See cb_sink docs for why the fps counter
     siv.set_fps(30);
@@ -214,7 +307,7 @@ fn main() {
             .build()
             .expect("Could not start tokio!");
 
-        rt.block_on(network_manager(client_rx, ui_sink));
+        rt.block_on(network_manager(keys, client_rx, ui_sink));
     });
 
     siv.run();
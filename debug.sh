@@ -1,7 +1,8 @@
 export RUST_LOG=info
 
+SERVER_ADDR="/ip4/127.0.0.1/tcp/8011"
 
-CLIENT_TERM="sleep 0.5; cd `pwd`; cargo run --bin message -- -r /ip4/127.0.0.1/tcp/8011"
+CLIENT_TERM="sleep 0.5; cd `pwd`; cargo run --bin message -- -r $SERVER_ADDR"
 kitty --detach sh -c "$CLIENT_TERM"
 
-cargo run --bin server
\ No newline at end of file
+cargo run --bin server -- -a $SERVER_ADDR
\ No newline at end of file
@@ -0,0 +1,231 @@
+//! Remembers every peer we have heard about so a restarted node can find its
+//! way back into the network without a bootnode.
+//!
+//! Addresses come from identify, mDNS and rendezvous and are scored by how
+//! often dialing them worked. Relayed addresses are left out since they only
+//! work as long as the relay does. The book is saved as JSON every few
+//! minutes and on shutdown.
+use libp2p::{Multiaddr, PeerId, multiaddr::Protocol};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Addresses that failed this many more times than they worked are forgotten
+const MAX_FAILURES: i64 = 5;
+/// Addresses kept for a single peer
+const MAX_ADDRS_PER_PEER: usize = 8;
+/// Peers kept in the book
+const MAX_PEERS: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+struct Score {
+    successes: u32,
+    failures: u32,
+}
+
+impl Score {
+    fn value(&self) -> i64 {
+        self.successes as i64 - self.failures as i64
+    }
+}
+
+/// On disk format
+#[derive(Serialize, Deserialize)]
+struct Record {
+    peer_id: PeerId,
+    addrs: Vec<(Multiaddr, Score)>,
+}
+
+#[derive(Default)]
+pub struct AddressBook {
+    /// Where the book is saved, `None` keeps it in memory only
+    path: Option<PathBuf>,
+    peers: HashMap<PeerId, HashMap<Multiaddr, Score>>,
+}
+
+impl AddressBook {
+    /// Loads the book saved at `path`. An empty book is returned if the file
+    /// doesn't exist yet.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut book = AddressBook {
+            path: None,
+            peers: HashMap::new(),
+        };
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let records: Vec<Record> = serde_json::from_slice(&bytes)?;
+                for record in records {
+                    for (addr, score) in record.addrs {
+                        if let Some(entry) = book.entry(record.peer_id, addr) {
+                            *entry = score;
+                        }
+                    }
+                }
+                info!(target: "addressbook", "Loaded {} peers from {}", book.peers.len(), path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        book.path = Some(path);
+        Ok(book)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let records: Vec<Record> = self
+            .peers
+            .iter()
+            .map(|(peer_id, addrs)| Record {
+                peer_id: *peer_id,
+                addrs: addrs.iter().map(|(a, s)| (a.clone(), *s)).collect(),
+            })
+            .collect();
+
+        // Write then rename so a crash can't leave us with half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&records)?)?;
+        fs::rename(&tmp, path)?;
+        info!(target: "addressbook", "Saved {} peers to {}", records.len(), path.display());
+        Ok(())
+    }
+
+    /// The score of `addr`, making room for it if the book is full. Gives
+    /// `None` if the address shouldn't be kept or everything already known
+    /// scores better than a newcomer.
+    fn entry(&mut self, peer_id: PeerId, addr: Multiaddr) -> Option<&mut Score> {
+        if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            return None;
+        }
+        if !self.peers.contains_key(&peer_id) && self.peers.len() >= MAX_PEERS {
+            let (worst, best) = self
+                .peers
+                .iter()
+                .map(|(peer_id, addrs)| {
+                    let best = addrs.values().map(Score::value).max().unwrap_or(0);
+                    (*peer_id, best)
+                })
+                .min_by_key(|(_, best)| *best)?;
+            if best > 0 {
+                return None;
+            }
+            self.peers.remove(&worst);
+        }
+
+        let addrs = self.peers.entry(peer_id).or_default();
+        if !addrs.contains_key(&addr) && addrs.len() >= MAX_ADDRS_PER_PEER {
+            let (worst, score) = addrs.iter().min_by_key(|(_, score)| score.value())?;
+            if score.value() > 0 {
+                return None;
+            }
+            let worst = worst.clone();
+            addrs.remove(&worst);
+        }
+        Some(addrs.entry(addr).or_default())
+    }
+
+    pub fn add(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        self.entry(peer_id, addr);
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        if let Some(addrs) = self.peers.get_mut(peer_id) {
+            addrs.remove(addr);
+            if addrs.is_empty() {
+                self.peers.remove(peer_id);
+            }
+        }
+    }
+
+    pub fn dial_succeeded(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        if let Some(score) = self.entry(peer_id, addr.clone()) {
+            score.successes += 1;
+        }
+    }
+
+    /// Only counts against addresses we already know about
+    pub fn dial_failed(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        let Some(score) = self.peers.get_mut(peer_id).and_then(|a| a.get_mut(addr)) else {
+            return;
+        };
+        score.failures += 1;
+
+        if score.value() <= -MAX_FAILURES {
+            self.remove(peer_id, addr);
+        }
+    }
+
+    /// Known addresses of the peer, best first
+    pub fn addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let Some(addrs) = self.peers.get(peer_id) else {
+            return Vec::new();
+        };
+
+        let mut addrs: Vec<(&Multiaddr, &Score)> = addrs.iter().collect();
+        addrs.sort_by_key(|(_, score)| -score.value());
+        addrs.into_iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    /// Up to `n` peers ordered by their best scoring address
+    pub fn best_peers(&self, n: usize) -> Vec<PeerId> {
+        let mut peers: Vec<(&PeerId, i64)> = self
+            .peers
+            .iter()
+            .map(|(peer_id, addrs)| {
+                let best = addrs.values().map(Score::value).max().unwrap_or(0);
+                (peer_id, best)
+            })
+            .collect();
+        peers.sort_by_key(|(_, best)| -best);
+        peers.into_iter().take(n).map(|(p, _)| *p).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_and_skips_relayed_addresses() {
+        let mut book = AddressBook::default();
+        let peer_id = PeerId::random();
+        let addr = |port: usize| format!("/ip4/10.0.0.1/tcp/{}", port).parse().unwrap();
+
+        let relayed = format!(
+            "/ip4/10.0.0.2/tcp/4001/p2p/{}/p2p-circuit",
+            PeerId::random()
+        );
+        book.dial_succeeded(peer_id, &relayed.parse().unwrap());
+        assert!(book.is_empty());
+
+        // Addresses that worked aren't pushed out by new ones
+        for port in 0..MAX_ADDRS_PER_PEER {
+            book.dial_succeeded(peer_id, &addr(port));
+        }
+        book.add(peer_id, addr(MAX_ADDRS_PER_PEER));
+        assert_eq!(book.addresses(&peer_id).len(), MAX_ADDRS_PER_PEER);
+        assert!(!book.addresses(&peer_id).contains(&addr(MAX_ADDRS_PER_PEER)));
+
+        for _ in 0..MAX_PEERS * 2 {
+            book.add(PeerId::random(), addr(0));
+        }
+        assert_eq!(book.len(), MAX_PEERS);
+        assert!(!book.addresses(&peer_id).is_empty());
+    }
+}
@@ -2,32 +2,67 @@
 //! to function. This includes network event handling, keeping track of network
 //! state, and the actual NetworkBehaviour. This is also where most of the libp2p
 //! code will live.
+use crate::discovery::Discovery;
 use libp2p::{
-    PeerId, autonat,
+    Multiaddr, PeerId, TransportError, autonat, dcutr,
     gossipsub::{self, Message, MessageAuthenticity},
     identify::{self, Info},
     identity::Keypair,
-    mdns, relay,
-    rendezvous::{self, Registration},
-    swarm::{NetworkBehaviour, behaviour::toggle::Toggle, dial_opts::DialOpts},
+    kad, mdns,
+    multiaddr::Protocol,
+    relay,
+    rendezvous::{self, Cookie, Namespace, Registration, Ttl},
+    swarm::{
+        FromSwarm, NetworkBehaviour, Swarm,
+        behaviour::{ExternalAddrConfirmed, toggle::Toggle},
+    },
 };
+use std::io;
+use std::time::Duration;
 use tracing::{debug, error, info, warn};
 
 pub const PROGRAM_PROTOCOL: &str = "magic-test/0.0.1";
 
+/// The Amino (public IPFS DHT) bootnodes
+pub const BOOTNODES: [&str; 4] = [
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+];
+
+/// How often the routing table gets refreshed
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 #[derive(NetworkBehaviour)]
 pub struct MainBehaviour {
     pub identify: identify::Behaviour,
     pub gossipsub: gossipsub::Behaviour,
     pub rendezvous: rendezvous::client::Behaviour,
     pub autonat: autonat::v2::client::Behaviour,
-    pub relay: relay::Behaviour,
+    pub relay: Toggle<relay::Behaviour>,
+    pub relay_client: relay::client::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
 
     pub mdns: Toggle<mdns::tokio::Behaviour>,
 }
 
 impl MainBehaviour {
-    pub fn new(keys: &Keypair, has_mdns: bool) -> Self {
+    /// `bootnodes` are the DHT peers used to bootstrap kademlia, they need to
+    /// end with `/p2p/<peer id>`. See [`BOOTNODES`] for the public ones.
+    ///
+    /// `discovery` decides which of mDNS and kademlia are run.
+    ///
+    /// `relay_client` comes from `SwarmBuilder::with_relay_client`. We only
+    /// run a relay server if `relay_cfg` is given, see [`MainBehaviour::enable_relay`].
+    pub fn new(
+        keys: &Keypair,
+        relay_client: relay::client::Behaviour,
+        relay_cfg: Option<relay::Config>,
+        discovery: &Discovery,
+        bootnodes: &[Multiaddr],
+    ) -> Self {
         let peer_id = keys.public().to_peer_id();
 
         let identify_cfg =
@@ -43,10 +78,13 @@ impl MainBehaviour {
 
         let autonat = autonat::v2::client::Behaviour::default();
 
-        let relay_cfg = relay::Config::default();
-        let relay = relay::Behaviour::new(peer_id, relay_cfg);
+        let relay = Toggle::from(relay_cfg.map(|cfg| relay::Behaviour::new(peer_id, cfg)));
+
+        let dcutr = dcutr::Behaviour::new(peer_id);
+
+        let kad = Toggle::from(discovery.kademlia().then(|| kademlia(peer_id, bootnodes)));
 
-        let mdns = if has_mdns {
+        let mdns = if discovery.mdns() {
             let mdns_cfg = mdns::Config::default();
             let mdns = mdns::tokio::Behaviour::new(mdns_cfg, peer_id).unwrap();
             Toggle::from(Some(mdns))
@@ -60,24 +98,143 @@ impl MainBehaviour {
             rendezvous,
             autonat,
             relay,
+            relay_client,
+            dcutr,
+            kad,
             mdns,
         }
     }
+
+    /// Starts the relay server if it isn't running yet. Only connections made
+    /// from now on will advertise and serve the hop protocol.
+    ///
+    /// The relay only learns our addresses from `ExternalAddrConfirmed` events,
+    /// so the ones the swarm confirmed before it existed are replayed from
+    /// `external_addrs`. Without them every reservation would carry no addrs.
+    pub fn enable_relay<'a>(
+        &mut self,
+        peer_id: PeerId,
+        relay_cfg: relay::Config,
+        external_addrs: impl IntoIterator<Item = &'a Multiaddr>,
+    ) {
+        if self.relay.is_enabled() {
+            return;
+        }
+
+        info!(target: "relay", "Starting relay server");
+        let mut relay = relay::Behaviour::new(peer_id, relay_cfg);
+        for addr in external_addrs {
+            relay.on_swarm_event(FromSwarm::ExternalAddrConfirmed(ExternalAddrConfirmed {
+                addr,
+            }));
+        }
+        self.relay = Toggle::from(Some(relay));
+    }
+
     pub fn get_peers(&mut self, node: &PeerId) {
         self.rendezvous.discover(None, None, None, *node);
     }
 }
 
+/// Listens on `addrs`, failing on the first one that can't be bound. If none
+/// were given it listens on `defaults` instead, skipping the ones this host
+/// can't bind (ie. without IPv6) as long as one of them works.
+pub fn listen<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    addrs: Vec<Multiaddr>,
+    defaults: &[&str],
+) -> Result<(), TransportError<io::Error>> {
+    if !addrs.is_empty() {
+        for addr in addrs {
+            swarm.listen_on(addr)?;
+        }
+        return Ok(());
+    }
+
+    let mut bound = false;
+    let mut last_error = None;
+    for addr in defaults {
+        let addr: Multiaddr = addr.parse().expect("Default addrs are valid");
+        match swarm.listen_on(addr.clone()) {
+            Ok(_) => bound = true,
+            Err(e) => {
+                warn!("Could not listen on {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    match last_error {
+        Some(e) if !bound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// A DHT client seeded with `bootnodes`
+fn kademlia(peer_id: PeerId, bootnodes: &[Multiaddr]) -> kad::Behaviour<kad::store::MemoryStore> {
+    let mut kad_cfg = kad::Config::new(kad::PROTOCOL_NAME);
+    kad_cfg.set_periodic_bootstrap_interval(Some(BOOTSTRAP_INTERVAL));
+    let store = kad::store::MemoryStore::new(peer_id);
+    let mut kad = kad::Behaviour::with_config(peer_id, store, kad_cfg);
+    // Stay a client until AutoNAT says otherwise, see `ConnectionMonitor::reachability`
+    kad.set_mode(Some(kad::Mode::Client));
+
+    for addr in bootnodes {
+        // We need the peer id at the end or dnsaddr won't resolve
+        match addr.iter().last() {
+            Some(Protocol::P2p(node)) => {
+                kad.add_address(&node, addr.clone());
+            }
+            _ => warn!(target: "kad", "Bootnode {} is missing its peer id, skipping", addr),
+        }
+    }
+    if let Err(e) = kad.bootstrap() {
+        warn!(target: "kad", "Could not bootstrap: {}", e);
+    }
+    kad
+}
+
 pub enum SwarmOpts {
+    Autonat(autonat::v2::client::Event),
     Message(Message),
-    Connect(Vec<Registration>),
-    Mdns(Vec<DialOpts>),
-    Identify(Info),
+    Connect {
+        rendezvous_node: PeerId,
+        registrations: Vec<Registration>,
+        cookie: Cookie,
+    },
+    DiscoverFailed {
+        rendezvous_node: PeerId,
+        namespace: Option<Namespace>,
+    },
+    Mdns(Vec<(PeerId, Multiaddr)>),
+    MdnsExpired(Vec<(PeerId, Multiaddr)>),
+    Identify(Box<Info>),
+    HolePunch(dcutr::Event),
+    Reservation(PeerId),
+    Relay(relay::Event),
+    Registered {
+        rendezvous_node: PeerId,
+        namespace: Namespace,
+        ttl: Ttl,
+    },
+    RegisterFailed {
+        rendezvous_node: PeerId,
+        namespace: Namespace,
+    },
 }
 
 pub fn behaviour_handle(event: MainBehaviourEvent) -> Option<SwarmOpts> {
     match event {
-        MainBehaviourEvent::Autonat(_) => None,
+        MainBehaviourEvent::Autonat(e) => {
+            match &e.result {
+                Ok(()) => {
+                    info!(target: "autonat", "<{}> confirmed {} is reachable", e.server, e.tested_addr)
+                }
+                Err(err) => {
+                    info!(target: "autonat", "<{}> could not reach {}: {}", e.server, e.tested_addr, err)
+                }
+            }
+            Some(SwarmOpts::Autonat(e))
+        }
         MainBehaviourEvent::Gossipsub(e) => match e {
             gossipsub::Event::Message { message, .. } => Some(SwarmOpts::Message(message)),
             gossipsub::Event::Subscribed { peer_id, topic } => {
@@ -99,7 +256,7 @@ pub fn behaviour_handle(event: MainBehaviourEvent) -> Option<SwarmOpts> {
                 info!(target: "identify", ?connection_id, "Found peer {}: supports {:#?}", peer_id, info.protocols);
                 debug!(target: "identify", ?connection_id, "supports addrs {:#?}", info.listen_addrs);
 
-                Some(SwarmOpts::Identify(info))
+                Some(SwarmOpts::Identify(Box::new(info)))
             }
             identify::Event::Error { peer_id, error, .. } => {
                 error!(target: "identify", "Error with {} getting peer info: {}", peer_id, error);
@@ -109,35 +266,87 @@ pub fn behaviour_handle(event: MainBehaviourEvent) -> Option<SwarmOpts> {
         },
         MainBehaviourEvent::Mdns(e) => match e {
             mdns::Event::Discovered(list) => {
-                let mut dials = Vec::new();
-                for (peer_id, addr) in list {
+                for (peer_id, _) in &list {
                     info!(target: "mDNS", "Adding {}", peer_id);
-
-                    let dial = DialOpts::peer_id(peer_id).addresses(vec![addr]).build();
-                    dials.push(dial);
                 }
-                Some(SwarmOpts::Mdns(dials))
+                Some(SwarmOpts::Mdns(list))
             }
             mdns::Event::Expired(list) => {
-                // This needs more testing to see if we need explicitly remove the addr
-                for (peer_id, _) in list {
+                for (peer_id, _) in &list {
                     info!(target: "mDNS", "Removing {}", peer_id);
                 }
+                Some(SwarmOpts::MdnsExpired(list))
+            }
+        },
+        MainBehaviourEvent::Relay(e) => Some(SwarmOpts::Relay(e)),
+        MainBehaviourEvent::RelayClient(e) => match e {
+            relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal,
+                ..
+            } => {
+                info!(target: "relay", renewal, "Reservation accepted by <{}>", relay_peer_id);
+                if renewal {
+                    None
+                } else {
+                    Some(SwarmOpts::Reservation(relay_peer_id))
+                }
+            }
+            e => {
+                debug!(target: "relay", "{:?}", e);
                 None
             }
         },
-        MainBehaviourEvent::Relay(_) => None,
-        MainBehaviourEvent::Rendezvous(e) => match e {
-            rendezvous::client::Event::Discovered { registrations, .. } => {
-                Some(SwarmOpts::Connect(registrations))
+        // Logged by `ConnectionMonitor::hole_punch`
+        MainBehaviourEvent::Dcutr(e) => Some(SwarmOpts::HolePunch(e)),
+        MainBehaviourEvent::Kad(e) => match e {
+            kad::Event::RoutingUpdated {
+                peer, is_new_peer, ..
+            } => {
+                if is_new_peer {
+                    debug!(target: "kad", "Added <{}> to routing table", peer);
+                }
+                None
             }
+            kad::Event::OutboundQueryProgressed {
+                result: kad::QueryResult::Bootstrap(result),
+                ..
+            } => {
+                match result {
+                    Ok(ok) => {
+                        debug!(target: "kad", "Bootstrapped with <{}>, {} remaining", ok.peer, ok.num_remaining)
+                    }
+                    Err(e) => warn!(target: "kad", "Bootstrap failed: {:?}", e),
+                }
+                None
+            }
+            kad::Event::ModeChanged { new_mode } => {
+                info!(target: "kad", "Now running in {} mode", new_mode);
+                None
+            }
+            _ => None,
+        },
+        MainBehaviourEvent::Rendezvous(e) => match e {
+            rendezvous::client::Event::Discovered {
+                rendezvous_node,
+                registrations,
+                cookie,
+            } => Some(SwarmOpts::Connect {
+                rendezvous_node,
+                registrations,
+                cookie,
+            }),
             rendezvous::client::Event::Registered {
                 rendezvous_node,
                 ttl,
                 namespace,
             } => {
                 info!(target: "rendezvous", ttl, "Regestered on {} to <{}>", namespace, rendezvous_node );
-                None
+                Some(SwarmOpts::Registered {
+                    rendezvous_node,
+                    namespace,
+                    ttl,
+                })
             }
             rendezvous::client::Event::DiscoverFailed {
                 rendezvous_node,
@@ -145,7 +354,10 @@ pub fn behaviour_handle(event: MainBehaviourEvent) -> Option<SwarmOpts> {
                 error,
             } => {
                 error!(target: "rendezvous", "Discover failed on <{}>:{:?} {:?}", rendezvous_node, namespace, error);
-                None
+                Some(SwarmOpts::DiscoverFailed {
+                    rendezvous_node,
+                    namespace,
+                })
             }
             rendezvous::client::Event::RegisterFailed {
                 rendezvous_node,
@@ -153,7 +365,10 @@ pub fn behaviour_handle(event: MainBehaviourEvent) -> Option<SwarmOpts> {
                 error,
             } => {
                 error!(target: "rendezvous", "Register failed on <{}>:{} {:?}", rendezvous_node, namespace, error);
-                None
+                Some(SwarmOpts::RegisterFailed {
+                    rendezvous_node,
+                    namespace,
+                })
             }
             _ => None,
         },
@@ -1,33 +1,47 @@
 //! Basic client for testing EVERYTHING
 
-use clap::Parser;
 use futures::StreamExt;
 use libp2p::swarm::{
     ConnectionId, NetworkBehaviour, Swarm, SwarmEvent, behaviour::toggle::Toggle,
     dial_opts::DialOpts,
 };
 use libp2p::{
-    Multiaddr, PeerId, SwarmBuilder, identify, identity::Keypair, mdns, noise, ping, relay::client,
-    rendezvous, tcp, yamux,
+    Multiaddr, PeerId, SwarmBuilder, dcutr, identify, mdns, multiaddr::Protocol, noise, ping,
+    relay::client, rendezvous, tcp, yamux,
+};
+use magicp2p::{
+    discovery::{Discovery, Method},
+    keystore,
 };
-use magicp2p::socket;
 use std::error::Error;
+use std::path::PathBuf;
 use tokio::{
-    select,
-    time::{self, Duration},
+    select, signal,
+    time::{self, Duration, Instant},
 };
 use tracing::{error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
 const PROGRAM_PROTOCOL: &str = "CONNECTION_TEST";
+const REGISTER_TTL: rendezvous::Ttl = 10000;
+const REGISTER_RETRY: Duration = Duration::from_secs(30);
+
+/// The discovery methods this binary has code for
+const DISCOVERY: [Method; 2] = [Method::Mdns, Method::Rendezvous];
 
-#[derive(Parser)]
+#[derive(clap::Parser)]
 #[command(version, about, long_about = None)]
 struct Opt {
-    #[arg(short, long)]
-    mdns: bool,
     #[arg(short, long)]
     relay: Option<String>,
+    /// Rendezvous namespace to register under and discover in
+    #[arg(short, long, default_value = "test")]
+    namespace: String,
+    /// Keyfile for the node identity, created if it doesn't exist.
+    #[arg(short, long, value_name = "path")]
+    identity: Option<PathBuf>,
+    #[command(flatten)]
+    discovery: Discovery,
 }
 
 #[derive(NetworkBehaviour)]
@@ -35,6 +49,7 @@ struct Behaviour {
     mdns: Toggle<mdns::tokio::Behaviour>,
     identify: identify::Behaviour,
     relay: client::Behaviour,
+    dcutr: dcutr::Behaviour,
     ping: ping::Behaviour,
     rendezvous: rendezvous::client::Behaviour,
 }
@@ -44,6 +59,8 @@ fn event_handle(
     event: SwarmEvent<BehaviourEvent>,
     server_id: &mut Option<PeerId>,
     server_connection: &mut Option<ConnectionId>,
+    register_at: &mut Option<Instant>,
+    cookie: &mut Option<rendezvous::Cookie>,
 ) {
     match event {
         SwarmEvent::NewListenAddr { address, .. } => info!("Listening on {}", address),
@@ -51,13 +68,23 @@ fn event_handle(
             peer_id,
             connection_id,
             endpoint,
-            num_established,
-            concurrent_dial_errors,
             established_in,
+            ..
         } => {
             if Some(connection_id) == *server_connection {
                 *server_connection = None;
                 *server_id = Some(peer_id);
+                *register_at = Some(Instant::now());
+
+                // Get a reservation so other clients can reach us through the relay
+                let circuit = endpoint
+                    .get_remote_address()
+                    .clone()
+                    .with(Protocol::P2p(peer_id))
+                    .with(Protocol::P2pCircuit);
+                if let Err(e) = swarm.listen_on(circuit) {
+                    error!("Could not listen on relay: {}", e);
+                }
             }
 
             info!(
@@ -65,18 +92,15 @@ fn event_handle(
                 connection_id, peer_id, endpoint, established_in
             );
         }
-        SwarmEvent::Behaviour(BehaviourEvent::Mdns(e)) => match e {
-            mdns::Event::Discovered(list) => {
-                for (peer_id, addr) in list {
-                    let dail = DialOpts::peer_id(peer_id)
-                        .addresses(vec![addr])
-                        .extend_addresses_through_behaviour()
-                        .build();
-                    let _ = swarm.dial(dail);
-                }
+        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+            for (peer_id, addr) in list {
+                let dail = DialOpts::peer_id(peer_id)
+                    .addresses(vec![addr])
+                    .extend_addresses_through_behaviour()
+                    .build();
+                let _ = swarm.dial(dail);
             }
-            _ => {}
-        },
+        }
         SwarmEvent::Behaviour(BehaviourEvent::Identify(e)) => match e {
             identify::Event::Received { peer_id, info, .. } => {
                 info!("<{}> supports {:#?}", peer_id, info.protocols);
@@ -89,15 +113,25 @@ fn event_handle(
         },
         SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(e)) => match e {
             rendezvous::client::Event::Discovered {
-                rendezvous_node,
                 registrations,
-                cookie,
+                cookie: next,
+                ..
             } => {
+                // Next time only ask for what we haven't seen
+                *cookie = Some(next);
+
                 for reg in registrations {
-                    let address = reg.record.addresses().get(0).unwrap();
+                    let peer_id = reg.record.peer_id();
+                    if peer_id == *swarm.local_peer_id() || swarm.is_connected(&peer_id) {
+                        continue;
+                    }
+                    if reg.record.addresses().is_empty() {
+                        warn!("<{}> registered without any addresses, skipping", peer_id);
+                        continue;
+                    }
 
                     let dail = DialOpts::peer_id(reg.record.peer_id())
-                        .addresses([address.clone()].to_vec())
+                        .addresses(reg.record.addresses().to_vec())
                         .extend_addresses_through_behaviour()
                         .build();
                     info!(
@@ -108,15 +142,43 @@ fn event_handle(
                     let _ = swarm.dial(dail);
                 }
             }
+            rendezvous::client::Event::Registered { ttl, .. } => {
+                // Renew at half the TTL so we never drop off the server
+                let refresh = Duration::from_secs(ttl) / 2;
+                info!("Registered for {}s, renewing in {:?}", ttl, refresh);
+                *register_at = Some(Instant::now() + refresh);
+            }
+            rendezvous::client::Event::DiscoverFailed { error, .. } => {
+                warn!("Discover failed: {:?}", error);
+                *cookie = None;
+            }
+            rendezvous::client::Event::RegisterFailed { error, .. } => {
+                warn!(
+                    "Register failed: {:?}, retrying in {:?}",
+                    error, REGISTER_RETRY
+                );
+                *register_at = Some(Instant::now() + REGISTER_RETRY);
+            }
             _ => warn!(?e),
         },
         SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => info!("Hi!"),
         SwarmEvent::Behaviour(BehaviourEvent::Relay(e)) => info!(target: "relay", ?e),
+        SwarmEvent::Behaviour(BehaviourEvent::Dcutr(e)) => match e.result {
+            Ok(id) => info!(target: "dcutr", "{} Hole punched to <{}>", id, e.remote_peer_id),
+            Err(err) => {
+                warn!(target: "dcutr", "Hole punch to <{}> failed: {}", e.remote_peer_id, err)
+            }
+        },
         _ => {}
     }
 }
 
-fn discover_handle(swarm: &mut Swarm<Behaviour>, server_id: Option<PeerId>) {
+fn discover_handle(
+    swarm: &mut Swarm<Behaviour>,
+    server_id: Option<PeerId>,
+    namespace: &rendezvous::Namespace,
+    cookie: Option<rendezvous::Cookie>,
+) {
     let peer_id = match server_id {
         Some(x) => {
             info!("Scanning <{}>", x);
@@ -131,24 +193,34 @@ fn discover_handle(swarm: &mut Swarm<Behaviour>, server_id: Option<PeerId>) {
     swarm
         .behaviour_mut()
         .rendezvous
-        .discover(None, None, None, peer_id);
+        .discover(Some(namespace.clone()), cookie, None, peer_id);
 }
 
-fn regester_handle(swarm: &mut Swarm<Behaviour>, rendezvous_node: &mut Option<PeerId>) {
-    let peer_id = match *rendezvous_node {
+/// Registers once `register_at` is due. It is set again when the server
+/// answers, see `event_handle`.
+fn regester_handle(
+    swarm: &mut Swarm<Behaviour>,
+    rendezvous_node: Option<PeerId>,
+    namespace: &rendezvous::Namespace,
+    register_at: &mut Option<Instant>,
+) {
+    let peer_id = match rendezvous_node {
         Some(x) => x,
         None => return,
     };
+    match *register_at {
+        Some(at) if at <= Instant::now() => *register_at = None,
+        _ => return,
+    }
 
-    *rendezvous_node = None;
-
-    if let Err(e) = swarm.behaviour_mut().rendezvous.register(
-        rendezvous::Namespace::from_static("test"),
-        peer_id,
-        Some(10000),
-    ) {
+    if let Err(e) =
+        swarm
+            .behaviour_mut()
+            .rendezvous
+            .register(namespace.clone(), peer_id, Some(REGISTER_TTL))
+    {
         error!(?e);
-        return;
+        *register_at = Some(Instant::now() + REGISTER_RETRY);
     };
 }
 
@@ -159,9 +231,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with(EnvFilter::from_default_env())
         .init();
 
-    let args = Opt::parse();
+    let args: Opt = Discovery::parse_with(&DISCOVERY, &DISCOVERY);
 
-    let keys = Keypair::generate_ed25519();
+    let keys = keystore::identity(args.identity.as_deref())?;
     let mut swarm = SwarmBuilder::with_existing_identity(keys)
         .with_tokio()
         .with_tcp(
@@ -173,7 +245,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_behaviour(|keys, relay| {
             let peer_id = keys.public().to_peer_id();
 
-            let mdns = if args.mdns {
+            let mdns = if args.discovery.mdns() {
                 let mdns_cfg = mdns::Config::default();
                 let mdns = mdns::tokio::Behaviour::new(mdns_cfg, peer_id).unwrap();
                 Toggle::from(Some(mdns))
@@ -187,11 +259,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             let rendezvous = rendezvous::client::Behaviour::new(keys.clone());
             let ping = ping::Behaviour::default();
+            let dcutr = dcutr::Behaviour::new(peer_id);
 
             Behaviour {
                 mdns,
                 identify,
                 relay,
+                dcutr,
                 rendezvous,
                 ping,
             }
@@ -207,18 +281,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let addr: Multiaddr = addr.parse()?;
         let request = DialOpts::unknown_peer_id().address(addr.clone()).build();
         relay_server = Some(request.connection_id());
-        let _ = swarm.dial(request).unwrap();
+        swarm.dial(request).unwrap();
         swarm.add_external_address(addr.clone());
     }
 
+    let namespace = rendezvous::Namespace::new(args.namespace)?;
+    let mut register_at: Option<Instant> = None;
+    let mut cookie: Option<rendezvous::Cookie> = None;
+
     let mut discover = time::interval(Duration::from_secs(5));
-    let mut regester = time::interval(Duration::from_secs(5));
+    let mut regester = time::interval(Duration::from_secs(1));
 
+    let rendezvous = args.discovery.rendezvous();
     loop {
         select! {
-            _ = discover.tick() => discover_handle(&mut swarm, relay_id),
-            _ = regester.tick() => regester_handle(&mut swarm, &mut relay_id),
-            event = swarm.select_next_some() => event_handle(&mut swarm, event, &mut relay_id, &mut relay_server)
+            _ = discover.tick(), if rendezvous => discover_handle(&mut swarm, relay_id, &namespace, cookie.clone()),
+            _ = regester.tick(), if rendezvous => regester_handle(&mut swarm, relay_id, &namespace, &mut register_at),
+            event = swarm.select_next_some() => event_handle(&mut swarm, event, &mut relay_id, &mut relay_server, &mut register_at, &mut cookie),
+            _ = signal::ctrl_c() => break,
         }
     }
+
+    if let Some(peer_id) = relay_id.filter(|_| rendezvous) {
+        swarm
+            .behaviour_mut()
+            .rendezvous
+            .unregister(namespace, peer_id);
+        // Poll for a bit so the unregister actually gets sent
+        let _ = time::timeout(Duration::from_secs(1), async {
+            loop {
+                swarm.select_next_some().await;
+            }
+        })
+        .await;
+    }
+
+    Ok(())
 }
@@ -1,28 +1,61 @@
 //! A basic messaging client for testing
 
-use clap::Parser;
 use futures::StreamExt;
-use libp2p::swarm::{DialError, NetworkBehaviour, Swarm, SwarmEvent, dial_opts::DialOpts};
-use libp2p::{Multiaddr, SwarmBuilder, gossipsub, identity::Keypair, noise, tcp, yamux};
-use magicp2p::socket;
+use libp2p::swarm::{
+    DialError, NetworkBehaviour, Swarm, SwarmEvent, behaviour::toggle::Toggle, dial_opts::DialOpts,
+};
+use libp2p::{Multiaddr, SwarmBuilder, gossipsub, mdns, noise, tcp, yamux};
+use magicp2p::{
+    behaviour,
+    discovery::{Discovery, Method},
+    keystore,
+    socket::{self, ForwardRequest, ResponseError, ResponseEvent},
+    unixsocket::LocalControl,
+};
+use std::collections::HashSet;
 use std::error::Error;
+use std::path::PathBuf;
 use std::thread;
 use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio::{runtime, select};
+use tokio::{runtime, select, signal};
 use tracing::{error, info, warn};
-use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+use tracing_subscriber::{EnvFilter, fmt, fmt::writer::BoxMakeWriter, prelude::*};
 
-#[derive(Parser)]
+const LISTEN_ADDRS: [&str; 2] = ["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"];
+
+/// The discovery methods this binary has code for, `--relay` is always dialed
+const DISCOVERY: [Method; 1] = [Method::Mdns];
+
+#[derive(clap::Parser)]
 #[command(version, about, long_about = None)]
 struct Opt {
     /// The address for remote server
     #[arg(short, long)]
     relay: Option<String>,
+    /// Keyfile for the node identity, created if it doesn't exist.
+    #[arg(short, long, value_name = "path")]
+    identity: Option<PathBuf>,
+    /// Where local clients connect. Keep this on loopback.
+    #[arg(
+        short,
+        long,
+        value_name = "addr",
+        default_value = "/ip4/127.0.0.1/udp/1234/quic-v1"
+    )]
+    socket: Multiaddr,
+    /// File with the PeerIds allowed to use the socket, one per line.
+    #[arg(short, long, value_name = "path")]
+    authorized_clients: Option<PathBuf>,
+    #[command(flatten)]
+    control: LocalControl,
+    #[command(flatten)]
+    discovery: Discovery,
 }
 
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
 }
 
 fn dial_unknown_peer(swarm: &mut Swarm<Behaviour>, addr: Multiaddr) -> Result<(), DialError> {
@@ -55,45 +88,105 @@ fn event_handle(
                 error!(?e);
             }
         }
+        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+            for (peer_id, addr) in list {
+                if swarm.is_connected(&peer_id) {
+                    continue;
+                }
+                let dial = DialOpts::peer_id(peer_id).addresses(vec![addr]).build();
+                if let Err(e) = swarm.dial(dial) {
+                    warn!("Failed to dial <{}> found through mDNS: {}", peer_id, e);
+                }
+            }
+        }
         _ => {}
     }
 }
 
-fn user_input_handle(swarm: &mut Swarm<Behaviour>, input: socket::ForwardRequest) {
+/// Acts on a request from the local client and answers it with the outcome
+fn user_input_handle(swarm: &mut Swarm<Behaviour>, input: ForwardRequest) {
     info!(?input);
-    /*let topic = gossipsub::IdentTopic::new(input.channel());
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
 
-    match input.kind() {
-        RequestType::JOIN => {
-            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
-                error!("{}: {}", e, input.channel());
-            }
+    let (response, reply) = match input {
+        ForwardRequest::Subscribe { channel, reply } => {
+            let topic = gossipsub::IdentTopic::new(channel);
+            let response = match gossipsub.subscribe(&topic) {
+                Ok(_) => ResponseEvent::Ok,
+                Err(e) => ResponseError::from(e).into(),
+            };
+            (response, reply)
         }
-        RequestType::PART => {
-            swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+        ForwardRequest::Unsubscribe { channel, reply } => {
+            let topic = gossipsub::IdentTopic::new(channel.as_str());
+            let response = if gossipsub.unsubscribe(&topic) {
+                ResponseEvent::Ok
+            } else {
+                ResponseError::NotSubscribed(channel).into()
+            };
+            (response, reply)
         }
-        RequestType::MESG(text) => {
-            if let Err(e) = swarm
-                .behaviour_mut()
-                .gossipsub
-                .publish(topic, text.as_bytes())
-            {
-                warn!("{e}")
-            }
+        ForwardRequest::Message {
+            data,
+            channel,
+            reply,
+        } => {
+            let topic = gossipsub::IdentTopic::new(channel);
+            let response = match gossipsub.publish(topic, data) {
+                Ok(id) => ResponseEvent::Published {
+                    message_id: id.to_string(),
+                },
+                Err(e) => ResponseError::from(e).into(),
+            };
+            (response, reply)
+        }
+        ForwardRequest::Channels { reply } => {
+            let channels = gossipsub.topics().map(|t| t.to_string()).collect();
+            (ResponseEvent::Channels(channels), reply)
+        }
+        ForwardRequest::Peers { channel, reply } => {
+            let count = match channel {
+                Some(channel) => {
+                    let hash = gossipsub::IdentTopic::new(channel).hash();
+                    gossipsub
+                        .all_peers()
+                        .filter(|(_, topics)| topics.contains(&&hash))
+                        .count()
+                }
+                None => gossipsub.all_peers().count(),
+            };
+            (ResponseEvent::Peers { count }, reply)
         }
-    }*/
+    };
+
+    if let ResponseEvent::Err(e) = &response {
+        warn!("{}", e);
+    }
+    // The socket thread is gone if this fails, nothing left to tell
+    let _ = reply.send(response);
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Opt = Discovery::parse_with(&DISCOVERY, &DISCOVERY);
+
+    // stdout belongs to the client in stdio mode
+    let writer = if args.control.stdio {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(writer))
         .with(EnvFilter::from_default_env())
         .init();
 
-    let args = Opt::parse();
-
-    let keys = Keypair::generate_ed25519();
+    let keys = keystore::identity(args.identity.as_deref())?;
+    let authorized = match &args.authorized_clients {
+        Some(path) => socket::authorized_clients(path)?,
+        None => HashSet::new(),
+    };
+    info!("{} authorized clients", authorized.len());
     let mut swarm = SwarmBuilder::with_existing_identity(keys)
         .with_tokio()
         .with_tcp(
@@ -109,28 +202,50 @@ async fn main() -> Result<(), Box<dyn Error>> {
             )
             .unwrap();
 
-            Behaviour { gossipsub }
+            let mdns = args.discovery.mdns().then(|| {
+                let peer_id = keys.public().to_peer_id();
+                mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id).unwrap()
+            });
+
+            Behaviour {
+                gossipsub,
+                mdns: Toggle::from(mdns),
+            }
         })?
         .build();
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-    swarm.listen_on("/ip6/::/tcp/0".parse()?)?;
+    behaviour::listen(&mut swarm, Vec::new(), &LISTEN_ADDRS)?;
 
     // Channel for keeping track of any requests that are made by the user (we are the receiver)
-    let (user_input_tx, mut user_input_rx) = mpsc::unbounded_channel::<socket::ForwardRequest>();
+    let (user_input_tx, mut user_input_rx) = mpsc::unbounded_channel::<ForwardRequest>();
     // Chennel for sending any gossipsub events to the user thread (we are the sender)
     let (mut message_tx, message_rx) = mpsc::unbounded_channel::<gossipsub::Event>();
 
+    // The client socket and control socket live on the socket thread's
+    // runtime but are opened here so failing to open them stops us like any
+    // other startup error
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let opened = {
+        let _rt = rt.enter();
+        socket::init_swarm(args.socket, &authorized)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|swarm| Ok((swarm, args.control.start()?)))
+    };
+    let (socket_swarm, (unix_rx, _socket_file)) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            // Dropping a runtime from inside our own would panic
+            rt.shutdown_background();
+            return Err(e);
+        }
+    };
+
     // Spawns a seperate thread that is just for handling user input
     thread::spawn(move || {
-        let rt = runtime::Builder::new_current_thread()
-            .enable_io()
-            .build()
-            .expect("Could not start tokio runtime");
-
-        let interfaces: Multiaddr = "/ip4/0.0.0.0/udp/1234/quic-v1".parse().unwrap();
-
         rt.block_on(socket::user_socket_handler(
-            interfaces,
+            socket_swarm,
+            unix_rx,
             user_input_tx,
             message_rx,
         ));
@@ -149,7 +264,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     loop {
         select! {
             Some(input) = user_input_rx.recv() => user_input_handle(&mut swarm, input),
-            event = swarm.select_next_some() => event_handle(&mut swarm, event, &mut message_tx)
+            event = swarm.select_next_some() => event_handle(&mut swarm, event, &mut message_tx),
+            _ = signal::ctrl_c() => break,
         }
     }
+
+    // Dropping `_socket_file` removes the control socket
+    Ok(())
 }
@@ -1,59 +1,146 @@
+use clap::ValueEnum;
 use futures::StreamExt;
 use libp2p::{
-    Swarm, SwarmBuilder,
+    Multiaddr, Swarm, SwarmBuilder,
     autonat::v2 as autonat,
     gossipsub, identify,
     identity::Keypair,
-    noise, relay, rendezvous,
-    swarm::{NetworkBehaviour, SwarmEvent},
+    mdns, noise, relay, rendezvous,
+    swarm::{NetworkBehaviour, SwarmEvent, behaviour::toggle::Toggle},
     tcp, yamux,
 };
-use magicp2p::{self, behaviour::PROGRAM_PROTOCOL};
+use magicp2p::{
+    self,
+    behaviour::{self, PROGRAM_PROTOCOL},
+    discovery::{Discovery, Method},
+    keystore,
+    registry::Registry,
+    relaylimits::{RelayLimits, RelayStats},
+    rendezvousserver,
+};
 use std::error::Error;
+use std::path::PathBuf;
+use tokio::{
+    select, signal,
+    time::{self, Duration},
+};
 use tracing::{error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
+const LISTEN_ADDRS: [&str; 4] = [
+    "/ip6/::/tcp/8011",
+    "/ip4/0.0.0.0/tcp/8011",
+    "/ip6/::/udp/8011/quic-v1",
+    "/ip4/0.0.0.0/udp/8011/quic-v1",
+];
+
+/// Services the server can run besides identify
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Service {
+    Relay,
+    Autonat,
+    Rendezvous,
+    Gossipsub,
+}
+
+/// The server only announces itself through mDNS so local nodes find it,
+/// it doesn't go looking for peers
+const DISCOVERY: [Method; 1] = [Method::Mdns];
+
+#[derive(clap::Parser)]
+#[command(version, about, long_about = None)]
+struct Opt {
+    /// Keyfile for the server identity, created if it doesn't exist.
+    #[arg(short, long, value_name = "path")]
+    identity: Option<PathBuf>,
+    /// multiaddrs the server will be listening on. Defaults to TCP and QUIC on port 8011 on both IPv4 and IPv6,
+    /// skipping whichever this host doesn't support.
+    #[arg(short, long, value_name = "multiaddr")]
+    address: Vec<Multiaddr>,
+    /// Public multiaddrs to announce, for servers behind a NAT or a load balancer.
+    #[arg(short, long, value_name = "multiaddr")]
+    external: Vec<Multiaddr>,
+    /// Services to turn off, all of them run by default.
+    #[arg(short, long, value_name = "service")]
+    disable: Vec<Service>,
+    /// Shortest TTL in seconds a peer may register with.
+    #[arg(long, value_name = "seconds", default_value_t = rendezvous::MIN_TTL)]
+    min_ttl: u64,
+    /// Longest TTL in seconds a peer may register with.
+    #[arg(long, value_name = "seconds", default_value_t = rendezvous::MAX_TTL)]
+    max_ttl: u64,
+    /// Namespaces a single peer may be registered in at once.
+    #[arg(long, value_name = "count", default_value_t = 32)]
+    max_per_peer: usize,
+    /// Peers that may be registered in a single namespace at once.
+    #[arg(long, value_name = "count", default_value_t = 1024)]
+    max_per_namespace: usize,
+    /// File where registrations are saved and served again from on start.
+    #[arg(long, value_name = "path")]
+    snapshot: Option<PathBuf>,
+    #[command(flatten)]
+    relay: RelayLimits,
+    #[command(flatten)]
+    discovery: Discovery,
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
-    gossipsub: gossipsub::Behaviour,
-    rendezvous: rendezvous::server::Behaviour,
+    gossipsub: Toggle<gossipsub::Behaviour>,
+    rendezvous: Toggle<rendezvousserver::Behaviour>,
     identify: identify::Behaviour,
-    autonat: autonat::server::Behaviour,
-    relay: relay::Behaviour,
+    autonat: Toggle<autonat::server::Behaviour>,
+    relay: Toggle<relay::Behaviour>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
 }
 
 impl Behaviour {
-    pub fn new(keys: &Keypair) -> Self {
+    pub fn new(
+        keys: &Keypair,
+        disabled: &[Service],
+        rendezvous: rendezvousserver::Behaviour,
+        relay_cfg: relay::Config,
+        discovery: &Discovery,
+    ) -> Self {
+        let enabled = |service| !disabled.contains(&service);
+
         let identify_cfg =
             identify::Config::new_with_signed_peer_record(PROGRAM_PROTOCOL.to_string(), keys);
         let identify = identify::Behaviour::new(identify_cfg);
 
-        let rendezvous_cfg = rendezvous::server::Config::default();
-        let rendezvous = rendezvous::server::Behaviour::new(rendezvous_cfg);
+        let rendezvous = enabled(Service::Rendezvous).then_some(rendezvous);
+
+        let autonat = enabled(Service::Autonat).then(autonat::server::Behaviour::default);
 
-        let autonat = autonat::server::Behaviour::default();
+        let relay = enabled(Service::Relay)
+            .then(|| relay::Behaviour::new(keys.public().to_peer_id(), relay_cfg));
 
-        let relay_cfg = relay::Config::default();
-        let relay = relay::Behaviour::new(keys.public().to_peer_id(), relay_cfg);
+        let gossipsub = enabled(Service::Gossipsub).then(|| {
+            let gossipsub_cfg = gossipsub::Config::default();
+            gossipsub::Behaviour::new(
+                gossipsub::MessageAuthenticity::Signed(keys.clone()),
+                gossipsub_cfg,
+            )
+            .unwrap()
+        });
 
-        let gossipsub_cfg = gossipsub::Config::default();
-        let gossipsub = gossipsub::Behaviour::new(
-            gossipsub::MessageAuthenticity::Signed(keys.clone()),
-            gossipsub_cfg,
-        )
-        .unwrap();
+        let mdns = discovery.mdns().then(|| {
+            let peer_id = keys.public().to_peer_id();
+            mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id).unwrap()
+        });
 
         Self {
-            gossipsub,
-            rendezvous,
+            gossipsub: Toggle::from(gossipsub),
+            rendezvous: Toggle::from(rendezvous),
             identify,
-            autonat,
-            relay,
+            autonat: Toggle::from(autonat),
+            relay: Toggle::from(relay),
+            mdns: Toggle::from(mdns),
         }
     }
 }
 
-fn network_handle(event: BehaviourEvent, swarm: &Swarm<Behaviour>) {
+fn network_handle(event: BehaviourEvent, swarm: &Swarm<Behaviour>, relay_stats: &mut RelayStats) {
     match event {
         BehaviourEvent::Identify(e) => match e {
             identify::Event::Received { peer_id, info, .. } => {
@@ -86,6 +173,10 @@ fn network_handle(event: BehaviourEvent, swarm: &Swarm<Behaviour>) {
             } => {
                 info!(target: "rendezvous", "Served <{}> {} registration(s)", enquirer, registrations.len());
             }
+            rendezvous::server::Event::RegistrationExpired(registration) => info!(
+                target: "rendezvous", "Expired: <{}> from {}",
+                registration.record.peer_id(), registration.namespace
+            ),
             e => warn!(?e),
         },
         BehaviourEvent::Autonat(e) => {
@@ -98,8 +189,9 @@ fn network_handle(event: BehaviourEvent, swarm: &Swarm<Behaviour>) {
                 );
             }
         }
-        BehaviourEvent::Relay(event) => info!("{:?}", event),
+        BehaviourEvent::Relay(event) => relay_stats.observe(&event),
         BehaviourEvent::Gossipsub(_) => {}
+        BehaviourEvent::Mdns(_) => {}
     }
 }
 
@@ -110,18 +202,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with(EnvFilter::from_default_env())
         .init();
 
-    let keys = Keypair::generate_ed25519();
-    let mut swarm = SwarmBuilder::with_existing_identity(keys.clone())
+    let args: Opt = Discovery::parse_with(&DISCOVERY, &[]);
+    if args.min_ttl > args.max_ttl {
+        return Err("--min-ttl can't be larger than --max-ttl".into());
+    }
+    let mut registry = Registry::new(args.max_per_peer, args.max_per_namespace);
+    if let Some(path) = args.snapshot {
+        registry = registry.open(path)?;
+    }
+    let rendezvous = rendezvousserver::Behaviour::new(registry, args.min_ttl, args.max_ttl);
+
+    let keys = keystore::identity(args.identity.as_deref())?;
+    let mut swarm = SwarmBuilder::with_existing_identity(keys)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
             noise::Config::new,
             yamux::Config::default,
         )?
-        .with_behaviour(|keys| Behaviour::new(keys))?
+        .with_quic()
+        .with_behaviour(|keys| {
+            Behaviour::new(
+                keys,
+                &args.disable,
+                rendezvous,
+                args.relay.config(),
+                &args.discovery,
+            )
+        })?
         .build();
-    swarm.listen_on("/ip6/::/tcp/8011".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/tcp/8011".parse()?)?;
+    behaviour::listen(&mut swarm, args.address, &LISTEN_ADDRS)?;
+    // Peers learn these through identify, which is what clients register
+    // and reserve relay slots with
+    for addr in args.external {
+        info!("Announcing {}", addr);
+        swarm.add_external_address(addr);
+    }
 
     println!("{}", magicp2p::BANNER);
 
@@ -129,24 +245,46 @@ async fn main() -> Result<(), Box<dyn Error>> {
      * I just want to have something that works
      */
     let temp_topic = gossipsub::IdentTopic::new("magic");
-    swarm.behaviour_mut().gossipsub.subscribe(&temp_topic)?;
+    if let Some(gossipsub) = swarm.behaviour_mut().gossipsub.as_mut() {
+        gossipsub.subscribe(&temp_topic)?;
+    }
+
+    let mut relay_stats = RelayStats::default();
+    let mut snapshot = time::interval(Duration::from_secs(60));
 
     loop {
-        let event = swarm.select_next_some().await;
+        let event = select! {
+            event = swarm.select_next_some() => event,
+            _ = snapshot.tick() => {
+                if let Some(rendezvous) = swarm.behaviour_mut().rendezvous.as_mut() {
+                    rendezvous.expire();
+                    if let Err(e) = rendezvous.registry().save() {
+                        error!("Failed to save registrations: {}", e);
+                    }
+                }
+                continue;
+            }
+            _ = signal::ctrl_c() => break,
+        };
         match event {
             SwarmEvent::NewListenAddr { address, .. } => info!("Listening on {}", address),
-            SwarmEvent::Behaviour(netinfo) => network_handle(netinfo, &swarm),
+            SwarmEvent::Behaviour(netinfo) => network_handle(netinfo, &swarm, &mut relay_stats),
             SwarmEvent::ConnectionEstablished {
                 peer_id,
                 established_in: inter,
                 ..
             } => info!("Connected to <{}> in {}ms", peer_id, inter.as_millis()),
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                if let Some(err) = cause {
-                    error!("Connection closed for <{}>: {}", peer_id, err)
-                }
-            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause: Some(err),
+                ..
+            } => error!("Connection closed for <{}>: {}", peer_id, err),
             _ => {}
         }
     }
+
+    if let Some(rendezvous) = swarm.behaviour().rendezvous.as_ref() {
+        rendezvous.registry().save()?;
+    }
+    Ok(())
 }
@@ -0,0 +1,86 @@
+//! How a node finds its peers. Shared by the binaries through
+//! `#[command(flatten)]` so they all take the same `--discovery` option.
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Method {
+    /// Peers on the local network
+    Mdns,
+    /// Peers registered in the same rooms on a rendezvous server
+    Rendezvous,
+    /// The public DHT
+    Kademlia,
+    /// Dialing the bootnodes given on the command line
+    Bootnodes,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct Discovery {
+    /// How peers are found, comma separated.
+    #[arg(long, value_name = "method", value_delimiter = ',', default_values = ["mdns", "rendezvous", "kademlia", "bootnodes"])]
+    pub discovery: Vec<Method>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            discovery: vec![
+                Method::Mdns,
+                Method::Rendezvous,
+                Method::Kademlia,
+                Method::Bootnodes,
+            ],
+        }
+    }
+}
+
+impl Method {
+    fn name(self) -> &'static str {
+        match self {
+            Method::Mdns => "mdns",
+            Method::Rendezvous => "rendezvous",
+            Method::Kademlia => "kademlia",
+            Method::Bootnodes => "bootnodes",
+        }
+    }
+}
+
+impl Discovery {
+    /// Parses the command line of a binary that only implements some of the
+    /// methods. Anything outside of `supported` is rejected by clap instead
+    /// of being silently ignored, and `default` replaces the usual default of
+    /// every method.
+    pub fn parse_with<T: Parser>(supported: &[Method], default: &[Method]) -> T {
+        let names: Vec<&'static str> = supported.iter().map(|m| m.name()).collect();
+        let default: Vec<&'static str> = default.iter().map(|m| m.name()).collect();
+        let mut cmd = T::command().mut_arg("discovery", |arg| {
+            arg.value_parser(
+                PossibleValuesParser::new(names).map(|s| Method::from_str(&s, false).unwrap()),
+            )
+            .default_values(default)
+        });
+        let mut matches = cmd.get_matches_mut();
+        T::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.format(&mut cmd).exit())
+    }
+
+    pub fn has(&self, method: Method) -> bool {
+        self.discovery.contains(&method)
+    }
+
+    pub fn mdns(&self) -> bool {
+        self.has(Method::Mdns)
+    }
+
+    pub fn rendezvous(&self) -> bool {
+        self.has(Method::Rendezvous)
+    }
+
+    pub fn kademlia(&self) -> bool {
+        self.has(Method::Kademlia)
+    }
+
+    pub fn bootnodes(&self) -> bool {
+        self.has(Method::Bootnodes)
+    }
+}
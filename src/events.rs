@@ -1,22 +1,37 @@
-use crate::behaviour::MainBehaviour;
-use libp2p::rendezvous::{Namespace, Registration};
-use libp2p::swarm::{ConnectionId, Swarm, dial_opts::DialOpts};
-use libp2p::{Multiaddr, PeerId, StreamProtocol, gossipsub::Message, identify::Info};
-use std::collections::{HashMap, HashSet};
-use std::mem;
-use std::time::SystemTime;
-use tracing::{error, info, warn};
+use crate::addressbook::AddressBook;
+use crate::behaviour::{MainBehaviour, MainBehaviourEvent};
+use crate::discovery::Discovery;
+use crate::relaylimits::RelayStats;
+use libp2p::core::ConnectedPoint;
+use libp2p::core::transport::ListenerId;
+use libp2p::rendezvous::{Cookie, Namespace, Registration, Ttl};
+use libp2p::swarm::{ConnectionId, DialError, Swarm, SwarmEvent, dial_opts::DialOpts};
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, autonat, dcutr, gossipsub, identify::Info, kad,
+    multiaddr::Protocol, relay,
+};
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, mem};
+use tracing::{debug, error, info, warn};
 
 const RENDEZVOUS_PROTOCOL: StreamProtocol = StreamProtocol::new("/rendezvous/1.0.0");
+/// How many closed connections are kept around for inspection
+const HISTORY_LEN: usize = 64;
+/// How many peers from the address book are dialed on start
+const RECONNECT_PEERS: usize = 16;
+/// Prefix of the rendezvous namespace of every room, see `room_namespace`
+const NAMESPACE: &str = "magic-test";
+/// Registrations are renewed after this fraction of their TTL has passed
+const REFRESH_FRACTION: f64 = 0.5;
+/// How long to wait before retrying a failed registration
+const REGISTER_RETRY: Duration = Duration::from_secs(30);
+/// Bounds for the exponential backoff used when redialing sticky peers
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
 
-enum SwarmOpts {
-    Mdns(Vec<DialOpts>),
-    Rendezvous(Vec<Registration>),
-    Identify(Info),
-    Gossipsub(Message),
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Status {
     Dialing,
     Connected,
@@ -29,83 +44,524 @@ impl PartialEq for Status {
     fn eq(&self, other: &Self) -> bool {
         mem::discriminant(self) == mem::discriminant(other)
     }
-    fn ne(&self, other: &Self) -> bool {
-        mem::discriminant(self) != mem::discriminant(other)
+}
+
+impl Status {
+    /// The lifecycle is `Dialing -> Connected -> Confirmed -> Closed` where
+    /// a connection can close at any point.
+    fn can_become(&self, next: &Status) -> bool {
+        matches!(
+            (self, next),
+            (Status::Dialing, Status::Connected)
+                | (Status::Connected, Status::Confirmed(_))
+                | (Status::Confirmed(_), Status::Confirmed(_))
+                | (Status::Dialing, Status::Closed)
+                | (Status::Connected, Status::Closed)
+                | (Status::Confirmed(_), Status::Closed)
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum MonitorError {
+    UnknownConnection(ConnectionId),
+    InvalidTransition {
+        id: ConnectionId,
+        from: Status,
+        to: Status,
+    },
+    /// The room name doesn't fit in a rendezvous namespace
+    InvalidRoom(String),
+    Subscription(gossipsub::SubscriptionError),
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MonitorError::UnknownConnection(id) => write!(f, "Unknown connection {}", id),
+            MonitorError::InvalidTransition { id, from, to } => {
+                write!(f, "Connection {} can't go from {:?} to {:?}", id, from, to)
+            }
+            MonitorError::InvalidRoom(room) => write!(f, "Invalid room name: {}", room),
+            MonitorError::Subscription(e) => write!(f, "Could not join room: {}", e),
+        }
     }
 }
 
-struct Connection {
-    peer_id: PeerId,
-    epoc: SystemTime,
-    status: Status,
+impl std::error::Error for MonitorError {}
+
+/// How the bytes of a connection get to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    /// Through a circuit relay, will be replaced by a direct connection if
+    /// hole punching works.
+    Relayed,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub id: ConnectionId,
+    /// Unknown while dialing an address without a `/p2p`
+    pub peer_id: Option<PeerId>,
+    /// Only known once connected
+    pub endpoint: Option<ConnectedPoint>,
+    /// When the connection was established
+    pub epoc: Option<SystemTime>,
+    pub status: Status,
+    /// Why the connection was closed, `None` if it was closed gracefully
+    pub cause: Option<String>,
+}
+
+impl Connection {
+    fn new(id: ConnectionId, peer_id: Option<PeerId>) -> Self {
+        Connection {
+            id,
+            peer_id,
+            endpoint: None,
+            epoc: None,
+            status: Status::Dialing,
+            cause: None,
+        }
+    }
+
+    pub fn route(&self) -> Option<Route> {
+        self.endpoint.as_ref().map(|endpoint| {
+            if endpoint.is_relayed() {
+                Route::Relayed
+            } else {
+                Route::Direct
+            }
+        })
+    }
+}
+
+/// A peer we always want to be connected to (bootnodes, rendezvous servers
+/// and relays). It gets redialed whenever the connection is lost.
+struct Sticky {
+    /// Unknown for bootnodes given without a `/p2p` until we first connect
+    peer_id: Option<PeerId>,
+    addrs: Vec<Multiaddr>,
+    /// Failed dials since we were last connected
+    attempts: u32,
+    /// When to try again, `None` while connected or dialing
+    redial_at: Option<Instant>,
+    /// The dial in flight
+    dialing: Option<ConnectionId>,
+}
+
+impl Sticky {
+    fn new(peer_id: Option<PeerId>, addrs: Vec<Multiaddr>) -> Self {
+        Sticky {
+            peer_id,
+            addrs,
+            attempts: 0,
+            redial_at: None,
+            dialing: None,
+        }
+    }
+
+    /// Schedules the next dial with a jittered exponential backoff, a random
+    /// delay between half and all of `BACKOFF_MIN * 2^attempts`.
+    fn backoff(&mut self) -> Duration {
+        let exp = BACKOFF_MIN.saturating_mul(2u32.saturating_pow(self.attempts));
+        let max = exp.min(BACKOFF_MAX);
+        let delay = rand::thread_rng().gen_range(max / 2..=max);
+
+        self.attempts += 1;
+        self.dialing = None;
+        self.redial_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    fn connected(&mut self, peer_id: PeerId) {
+        self.peer_id = Some(peer_id);
+        self.attempts = 0;
+        self.redial_at = None;
+        self.dialing = None;
+    }
+
+    fn dial_opts(&self) -> DialOpts {
+        match self.peer_id {
+            Some(peer_id) => DialOpts::peer_id(peer_id)
+                .addresses(self.addrs.clone())
+                .extend_addresses_through_behaviour()
+                .build(),
+            None => DialOpts::unknown_peer_id()
+                .address(self.addrs[0].clone())
+                .build(),
+        }
+    }
 }
 
 pub struct ConnectionMonitor {
     local_peer_id: PeerId,
     swarm: Swarm<MainBehaviour>,
-    connections: HashMap<ConnectionId, Status>,
+    connections: HashMap<ConnectionId, Connection>,
+    /// The last `HISTORY_LEN` closed connections
+    history: VecDeque<Connection>,
     rendezvous: HashSet<PeerId>,
+    /// Relays we have (or are asking for) a reservation on
+    relays: HashMap<PeerId, ListenerId>,
+    /// Last address we successfully dialed each peer on
+    dialed_addrs: HashMap<PeerId, Multiaddr>,
+    /// Addresses AutoNAT has confirmed as publicly reachable
+    reachable: HashSet<Multiaddr>,
+    book: AddressBook,
+    sticky: Vec<Sticky>,
+    /// When each of our registrations has to be renewed
+    registrations: HashMap<(PeerId, Namespace), Instant>,
+    /// Where the last discover on each server and namespace left off
+    cookies: HashMap<(PeerId, Option<Namespace>), Cookie>,
+    /// Rendezvous namespaces of the rooms we joined
+    namespaces: HashSet<Namespace>,
+    /// What others have done with our relay server
+    relay_stats: RelayStats,
+    /// Relay server config held back until AutoNAT confirms we are reachable
+    pending_relay: Option<relay::Config>,
+    discovery: Discovery,
 }
 
 impl ConnectionMonitor {
-    pub fn new(swarm: Swarm<MainBehaviour>) -> Self {
+    pub fn new(swarm: Swarm<MainBehaviour>, book: AddressBook, discovery: Discovery) -> Self {
         ConnectionMonitor {
             local_peer_id: *swarm.local_peer_id(),
             swarm,
             connections: HashMap::new(),
+            history: VecDeque::new(),
             rendezvous: HashSet::new(),
+            relays: HashMap::new(),
+            dialed_addrs: HashMap::new(),
+            reachable: HashSet::new(),
+            book,
+            sticky: Vec::new(),
+            registrations: HashMap::new(),
+            cookies: HashMap::new(),
+            namespaces: HashSet::new(),
+            relay_stats: RelayStats::default(),
+            pending_relay: None,
+            discovery,
         }
     }
     /// Helper function that manages connections. It manages dial requests because
     /// libp2p will throw errors if we dail a peer multiple times
     pub fn dial(&mut self, request: DialOpts) {
         let id = request.connection_id();
-        if let Some(s) = self.connections.get(&id) {
-            if *s == Status::Dialing {
-                return;
-            }
+        let peer_id = request.get_peer_id();
+        if peer_id.is_some_and(|p| self.is_dialing(&p)) {
+            return;
         }
 
         if let Err(e) = self.swarm.dial(request) {
             error!(target: "monitor", "Could not dial peer: {}", e);
+            return;
         }
 
-        self.connections.insert(id, Status::Dialing);
+        self.connections.insert(id, Connection::new(id, peer_id));
+    }
+
+    fn is_dialing(&self, peer_id: &PeerId) -> bool {
+        self.connections
+            .values()
+            .any(|c| c.peer_id.as_ref() == Some(peer_id) && c.status == Status::Dialing)
     }
-    /// Update the status of a connection
-    pub fn update_status(&mut self, id: ConnectionId, status: Status) {
-        if !self.connections.contains_key(&id) {
-            error!(target: "monitor", "Tried to change status of a unknown ConnectionId: {} to {:?}", id, status)
+
+    /// Update the status of a connection, moving it into the history once closed.
+    pub fn update_status(&mut self, id: ConnectionId, status: Status) -> Result<(), MonitorError> {
+        let connection = self
+            .connections
+            .get_mut(&id)
+            .ok_or(MonitorError::UnknownConnection(id))?;
+
+        if !connection.status.can_become(&status) {
+            return Err(MonitorError::InvalidTransition {
+                id,
+                from: connection.status.clone(),
+                to: status,
+            });
+        }
+
+        if let Status::Confirmed(addr) = &status {
+            self.swarm.add_external_address(addr.clone());
+        }
+        connection.status = status;
+
+        if connection.status == Status::Closed {
+            let connection = self.connections.remove(&id).expect("Checked above");
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(connection);
         }
+        Ok(())
+    }
 
-        let current = self.connections.get(&id).expect("Wont fail");
+    /// Drives the connection lifecycle from the swarm, should be given every event.
+    pub fn track(&mut self, event: &SwarmEvent<MainBehaviourEvent>) -> Result<(), MonitorError> {
+        match event {
+            SwarmEvent::Dialing {
+                peer_id,
+                connection_id,
+            } => {
+                // Dials from `dial()` are already known, behaviours dial on their own
+                self.connections
+                    .entry(*connection_id)
+                    .or_insert_with(|| Connection::new(*connection_id, *peer_id));
+                Ok(())
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                // Inbound connections never go through `Dialing`
+                let connection = self
+                    .connections
+                    .entry(*connection_id)
+                    .or_insert_with(|| Connection::new(*connection_id, Some(*peer_id)));
+                connection.peer_id = Some(*peer_id);
+                connection.endpoint = Some(endpoint.clone());
+                connection.epoc = Some(SystemTime::now());
 
-        match status {
-            Status::Closed => {
-                if *current != Status::Confirmed(Multiaddr::empty())
-                    || *current != Status::Connected
+                if let Some(sticky) = self
+                    .sticky
+                    .iter_mut()
+                    .find(|s| s.dialing == Some(*connection_id) || s.peer_id == Some(*peer_id))
                 {
-                    panic!("Invalid state for status");
+                    sticky.connected(*peer_id);
                 }
-                self.connections.remove(&id);
+
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    self.book.dial_succeeded(*peer_id, address);
+                    if !endpoint.is_relayed() {
+                        self.dialed_addrs.insert(*peer_id, address.clone());
+                    }
+                }
+                self.update_status(*connection_id, Status::Connected)
             }
-            Status::Confirmed(addr) => {
-                if *current != Status::Connected {
-                    panic!("Invalid state for status");
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                cause,
+                ..
+            } => {
+                if *num_established == 0 {
+                    self.disconnected(*peer_id);
                 }
-                self.swarm.add_external_address(addr);
+                self.close(*connection_id, cause.as_ref().map(|e| e.to_string()))
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if let (Some(peer_id), DialError::Transport(errors)) = (peer_id, error) {
+                    for (addr, _) in errors {
+                        self.book.dial_failed(peer_id, addr);
+                    }
+                }
+                if let Some(sticky) = self
+                    .sticky
+                    .iter_mut()
+                    .find(|s| s.dialing == Some(*connection_id))
+                {
+                    let delay = sticky.backoff();
+                    warn!(target: "monitor", "Could not reach {:?}, retrying in {:?}", sticky.addrs, delay);
+                }
+                // Dials that fail before `Dialing` (ie. without any addresses)
+                // were never tracked, there is nothing to close
+                if !self.connections.contains_key(connection_id) {
+                    debug!(target: "monitor", "Dial {} failed before it started: {}", connection_id, error);
+                    return Ok(());
+                }
+                self.close(*connection_id, Some(error.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The last connection to `peer_id` is gone
+    fn disconnected(&mut self, peer_id: PeerId) {
+        if self.rendezvous.remove(&peer_id) {
+            warn!(target: "monitor", "Rendezvous server <{}> is unreachable", peer_id);
+            // It might come back with an empty registry
+            self.cookies.retain(|(server, _), _| *server != peer_id);
+        }
+
+        if let Some(sticky) = self.sticky.iter_mut().find(|s| s.peer_id == Some(peer_id)) {
+            let delay = sticky.backoff();
+            info!(target: "monitor", "Lost <{}>, redialing in {:?}", peer_id, delay);
+        }
+    }
+
+    /// Dials a bootnode and keeps redialing it whenever the connection drops.
+    /// The peer id is learned on the first connection if `addr` doesn't have it.
+    pub fn dial_bootnode(&mut self, addr: Multiaddr) {
+        let peer_id = match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Some(peer_id),
+            _ => None,
+        };
+        self.add_sticky(peer_id, vec![addr]);
+    }
+
+    /// Marks a peer as one we always want to be connected to
+    fn make_sticky(&mut self, peer_id: PeerId) {
+        let addrs = match self.dialed_addrs.get(&peer_id) {
+            Some(addr) => vec![addr.clone()],
+            None => self.book.addresses(&peer_id),
+        };
+        self.add_sticky(Some(peer_id), addrs);
+    }
+
+    fn add_sticky(&mut self, peer_id: Option<PeerId>, addrs: Vec<Multiaddr>) {
+        if addrs.is_empty() {
+            return;
+        }
+        if peer_id.is_some() && self.sticky.iter().any(|s| s.peer_id == peer_id) {
+            return;
+        }
+
+        let mut sticky = Sticky::new(peer_id, addrs);
+        if !peer_id.is_some_and(|p| self.swarm.is_connected(&p)) {
+            // Dial right away on the next `redial()`
+            sticky.redial_at = Some(Instant::now());
+        }
+        self.sticky.push(sticky);
+    }
+
+    /// Redials sticky peers whose backoff has run out. Should be called periodically.
+    pub fn redial(&mut self) {
+        let now = Instant::now();
+        for sticky in &mut self.sticky {
+            if sticky.redial_at.is_none_or(|at| at > now) {
+                continue;
             }
-            Status::Connected => {
-                if *current != Status::Dialing {
-                    panic!("Invalid state for status");
+            if sticky.peer_id.is_some_and(|p| self.swarm.is_connected(&p)) {
+                sticky.redial_at = None;
+                continue;
+            }
+
+            let dial = sticky.dial_opts();
+            let id = dial.connection_id();
+            match self.swarm.dial(dial) {
+                Ok(()) => {
+                    sticky.dialing = Some(id);
+                    sticky.redial_at = None;
+                    self.connections
+                        .insert(id, Connection::new(id, sticky.peer_id));
                 }
-                self.connections.insert(id, status);
+                Err(e) => {
+                    let delay = sticky.backoff();
+                    warn!(target: "monitor", "Could not dial {:?}: {}, retrying in {:?}", sticky.addrs, e, delay);
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, id: ConnectionId, cause: Option<String>) -> Result<(), MonitorError> {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            debug!(target: "monitor", "Closing {} to {:?}: {:?}", id, connection.peer_id, cause);
+            connection.cause = cause;
+        }
+        self.update_status(id, Status::Closed)
+    }
+
+    /// Adds addresses we learned about a peer to the address book
+    pub fn remember(&mut self, peer_id: PeerId, addrs: impl IntoIterator<Item = Multiaddr>) {
+        if peer_id == self.local_peer_id {
+            return;
+        }
+        for addr in addrs {
+            self.book.add(peer_id, addr);
+        }
+    }
+
+    /// Address book entry expired (ie. mDNS), removes it from the book
+    pub fn forget(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        self.book.remove(peer_id, addr);
+    }
+
+    /// Remembers peers found on the LAN and dials the ones we aren't
+    /// connected to yet
+    pub fn mdns_discovered(&mut self, list: Vec<(PeerId, Multiaddr)>) {
+        let mut found: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+        for (peer_id, addr) in list {
+            found.entry(peer_id).or_default().push(addr);
+        }
+
+        for (peer_id, addrs) in found {
+            self.remember(peer_id, addrs.iter().cloned());
+            if peer_id == self.local_peer_id || self.swarm.is_connected(&peer_id) {
+                continue;
+            }
+            self.dial(DialOpts::peer_id(peer_id).addresses(addrs).build());
+        }
+    }
+
+    /// The peer stopped answering mDNS on these addresses, they are most
+    /// likely gone so don't keep handing them out
+    pub fn mdns_expired(&mut self, list: Vec<(PeerId, Multiaddr)>) {
+        for (peer_id, addr) in list {
+            self.forget(&peer_id, &addr);
+            if let Some(kad) = self.behaviour_mut().kad.as_mut() {
+                kad.remove_address(&peer_id, &addr);
+            }
+        }
+    }
+
+    /// Dials the best peers from the address book. Used on start so we don't
+    /// depend on a bootnode being up.
+    pub fn reconnect_known(&mut self) {
+        for peer_id in self.book.best_peers(RECONNECT_PEERS) {
+            let addrs = self.book.addresses(&peer_id);
+            info!(target: "monitor", "Reconnecting to known peer <{}>", peer_id);
+            self.dial(DialOpts::peer_id(peer_id).addresses(addrs).build());
+        }
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.book
+    }
+
+    /// Should be called on shutdown
+    pub fn save_peers(&self) {
+        if let Err(e) = self.book.save() {
+            error!(target: "monitor", "Could not save address book: {}", e);
+        }
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<&Connection> {
+        self.connections.get(&id)
+    }
+
+    /// Recently closed connections, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &Connection> {
+        self.history.iter()
+    }
+
+    pub fn route(&self, id: ConnectionId) -> Option<Route> {
+        self.connection(id).and_then(Connection::route)
+    }
+
+    /// Handles the outcome of a DCUtR upgrade. On success `result` holds the
+    /// new direct connection, the relayed one will be closed by the remote.
+    pub fn hole_punch(&mut self, event: dcutr::Event) {
+        match event.result {
+            Ok(id) => {
+                info!(target: "monitor", "Upgraded <{}> to a direct connection {} ({:?})", event.remote_peer_id, id, self.route(id));
+            }
+            Err(e) => {
+                warn!(target: "monitor", "Staying relayed to <{}>: {}", event.remote_peer_id, e);
             }
-            Status::Dialing => {}
         }
     }
 
     pub fn regester(&mut self, info: &Info) {
+        if !self.discovery.rendezvous() {
+            return;
+        }
         let remote_id = info.public_key.to_peer_id();
         if remote_id == self.local_peer_id {
             warn!(target: "monitor", "got local id from identify {:#?}", info);
@@ -115,18 +571,361 @@ impl ConnectionMonitor {
         for proto in &info.protocols {
             if *proto == RENDEZVOUS_PROTOCOL {
                 self.rendezvous.insert(remote_id);
-                if let Err(err) = self.behaviour_mut().rendezvous.register(
-                    Namespace::from_static("magic-test"),
-                    remote_id,
-                    None,
-                ) {
-                    error!(target: "monitor", "Could not regester: {}", err);
-                }
+                self.make_sticky(remote_id);
+                self.register(remote_id);
                 info!(target: "monitor", "Found rendezvous server: {}/{}", info.observed_addr, remote_id);
             }
         }
     }
 
+    fn register(&mut self, server: PeerId) {
+        let namespaces: Vec<Namespace> = self.namespaces.iter().cloned().collect();
+        for namespace in namespaces {
+            self.register_namespace(server, namespace);
+        }
+    }
+
+    /// Joins the gossipsub channel `room` and registers under its namespace on
+    /// every rendezvous server so that only peers sharing a room find each other.
+    pub fn join(&mut self, room: &str) -> Result<(), MonitorError> {
+        let namespace = room_namespace(room)?;
+        let topic = gossipsub::IdentTopic::new(room);
+        self.behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .map_err(MonitorError::Subscription)?;
+
+        if !self.namespaces.insert(namespace.clone()) {
+            return Ok(());
+        }
+        info!(target: "monitor", "Joined {}", namespace);
+
+        let servers: Vec<PeerId> = self.rendezvous.iter().copied().collect();
+        for server in servers {
+            self.register_namespace(server, namespace.clone());
+        }
+        self.discover();
+        Ok(())
+    }
+
+    /// Leaves the room and removes our registrations for it
+    pub fn part(&mut self, room: &str) -> Result<(), MonitorError> {
+        let namespace = room_namespace(room)?;
+        let topic = gossipsub::IdentTopic::new(room);
+        self.behaviour_mut().gossipsub.unsubscribe(&topic);
+
+        if !self.namespaces.remove(&namespace) {
+            return Ok(());
+        }
+        info!(target: "monitor", "Left {}", namespace);
+
+        self.cookies
+            .retain(|(_, ns), _| ns.as_ref() != Some(&namespace));
+        let servers: Vec<PeerId> = self.rendezvous.iter().copied().collect();
+        for server in servers {
+            self.registrations.remove(&(server, namespace.clone()));
+            self.behaviour_mut()
+                .rendezvous
+                .unregister(namespace.clone(), server);
+        }
+        Ok(())
+    }
+
+    /// Namespaces we register under and discover in
+    pub fn namespaces(&self) -> impl Iterator<Item = &Namespace> {
+        self.namespaces.iter()
+    }
+
+    fn register_namespace(&mut self, server: PeerId, namespace: Namespace) {
+        if let Err(err) = self
+            .behaviour_mut()
+            .rendezvous
+            .register(namespace.clone(), server, None)
+        {
+            error!(target: "monitor", "Could not regester: {}", err);
+            // Most likely we don't know our external addrs yet
+            self.registrations
+                .insert((server, namespace), Instant::now() + REGISTER_RETRY);
+        }
+    }
+
+    /// Schedules the renewal of a registration before its TTL runs out
+    pub fn registered(&mut self, server: PeerId, namespace: Namespace, ttl: Ttl) {
+        // We left the room before the server answered
+        if !self.namespaces.contains(&namespace) {
+            return;
+        }
+        let refresh = Duration::from_secs(ttl).mul_f64(REFRESH_FRACTION);
+        debug!(target: "monitor", "Renewing {} on <{}> in {:?}", namespace, server, refresh);
+        self.registrations
+            .insert((server, namespace), Instant::now() + refresh);
+    }
+
+    pub fn register_failed(&mut self, server: PeerId, namespace: Namespace) {
+        if !self.namespaces.contains(&namespace) {
+            return;
+        }
+        self.registrations
+            .insert((server, namespace), Instant::now() + REGISTER_RETRY);
+    }
+
+    /// Renews registrations that are due. Should be called periodically.
+    pub fn refresh_registrations(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(PeerId, Namespace)> = self
+            .registrations
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for (server, namespace) in due {
+            self.registrations.remove(&(server, namespace.clone()));
+            // Will be registered again once the server is back
+            if self.rendezvous.contains(&server) {
+                self.register_namespace(server, namespace);
+            }
+        }
+    }
+
+    /// Removes all our registrations, should be called on graceful shutdown.
+    /// The swarm still needs to be polled for the requests to go out.
+    pub fn unregister_all(&mut self) {
+        let registrations: Vec<(PeerId, Namespace)> =
+            self.registrations.drain().map(|(k, _)| k).collect();
+        for (server, namespace) in registrations {
+            info!(target: "monitor", "Unregistering {} from <{}>", namespace, server);
+            self.behaviour_mut()
+                .rendezvous
+                .unregister(namespace, server);
+        }
+    }
+
+    /// Asks every rendezvous server for registrations added since the last
+    /// time we asked.
+    pub fn discover(&mut self) {
+        let servers: Vec<PeerId> = self.rendezvous.iter().copied().collect();
+        let namespaces: Vec<Namespace> = self.namespaces.iter().cloned().collect();
+        for server in servers {
+            for namespace in &namespaces {
+                let namespace = Some(namespace.clone());
+                let cookie = self.cookies.get(&(server, namespace.clone())).cloned();
+                debug!(target: "monitor", "Scanning <{}> for {:?} (incremental: {})", server, namespace, cookie.is_some());
+                self.behaviour_mut()
+                    .rendezvous
+                    .discover(namespace, cookie, None, server);
+            }
+        }
+    }
+
+    /// Dials the newly discovered peers we aren't connected to yet
+    pub fn discovered(&mut self, server: PeerId, registrations: Vec<Registration>, cookie: Cookie) {
+        self.cookies
+            .insert((server, cookie.namespace().cloned()), cookie);
+
+        for registration in registrations {
+            let record = registration.record;
+            let peer_id = record.peer_id();
+            self.remember(peer_id, record.addresses().to_vec());
+
+            if peer_id == self.local_peer_id || self.swarm.is_connected(&peer_id) {
+                continue;
+            }
+            let dial_request = DialOpts::peer_id(peer_id)
+                .addresses(record.addresses().to_vec())
+                .build();
+            self.dial(dial_request);
+        }
+    }
+
+    /// The cookie could have been rejected so start over
+    pub fn discover_failed(&mut self, server: PeerId, namespace: Option<Namespace>) {
+        self.cookies.remove(&(server, namespace));
+    }
+
+    /// Asks the peer for a relay reservation if it is a relay server. Once
+    /// accepted we can be reached through `/p2p/<relay>/p2p-circuit`.
+    pub fn reserve(&mut self, info: &Info) {
+        let remote_id = info.public_key.to_peer_id();
+        if remote_id == self.local_peer_id
+            || self.relays.contains_key(&remote_id)
+            || !info.protocols.contains(&relay::HOP_PROTOCOL_NAME)
+        {
+            return;
+        }
+
+        // The address we reached them on is the most likely to work, the
+        // listen addrs could be anything.
+        let relay_addr = match self.dialed_addrs.get(&remote_id) {
+            Some(addr) => addr.clone(),
+            None => match info
+                .listen_addrs
+                .iter()
+                .find(|addr| !addr.iter().any(|p| p == Protocol::P2pCircuit))
+            {
+                Some(addr) => addr.clone(),
+                None => return,
+            },
+        };
+
+        let circuit = relay_addr
+            .with_p2p(remote_id)
+            .unwrap_or_else(|addr| addr)
+            .with(Protocol::P2pCircuit);
+        match self.swarm.listen_on(circuit.clone()) {
+            Ok(id) => {
+                info!(target: "monitor", "Requesting reservation on {}", circuit);
+                self.relays.insert(remote_id, id);
+                self.make_sticky(remote_id);
+            }
+            Err(e) => error!(target: "monitor", "Could not listen on {}: {}", circuit, e),
+        }
+    }
+
+    /// Called once a relay accepts our reservation. The relay client has
+    /// confirmed the circuit addr as external by now, so re-register to make
+    /// sure our rendezvous records include it.
+    pub fn reservation_accepted(&mut self, relay: PeerId) {
+        info!(target: "monitor", "Reachable through <{}>", relay);
+        let servers: Vec<PeerId> = self.rendezvous.iter().copied().collect();
+        for server in servers {
+            self.register(server);
+        }
+    }
+
+    /// Starts our relay server with `cfg` the first time AutoNAT confirms one
+    /// of our addresses. It keeps running even if we later lose reachability,
+    /// stopping it would cut the circuits it's carrying.
+    pub fn relay_when_reachable(&mut self, cfg: relay::Config) {
+        if self.reachable.is_empty() {
+            self.pending_relay = Some(cfg);
+        } else {
+            self.start_relay(cfg);
+        }
+    }
+
+    /// Hands the relay server every address the swarm already confirmed, it
+    /// missed those events while it didn't exist.
+    fn start_relay(&mut self, cfg: relay::Config) {
+        let peer_id = self.local_peer_id;
+        let external: Vec<Multiaddr> = self.swarm.external_addresses().cloned().collect();
+        self.behaviour_mut().enable_relay(peer_id, cfg, &external);
+    }
+
+    /// Events from our own relay server, as opposed to the relays we reserve on
+    pub fn relay_event(&mut self, event: &relay::Event) {
+        self.relay_stats.observe(event);
+    }
+
+    pub fn relay_stats(&self) -> RelayStats {
+        self.relay_stats
+    }
+
+    /// Forgets a reservation once its listener goes away so that we can ask
+    /// again next time the relay identifies itself. Whatever was confirmed
+    /// reachable on `addrs` isn't anymore.
+    pub fn listener_closed(&mut self, id: ListenerId, addrs: &[Multiaddr]) {
+        self.relays.retain(|relay, listener| {
+            if *listener == id {
+                warn!(target: "monitor", "Lost reservation on <{}>", relay);
+            }
+            *listener != id
+        });
+
+        let mut lost = false;
+        for addr in addrs {
+            if self.reachable.remove(addr) {
+                self.swarm.remove_external_address(addr);
+                lost = true;
+            }
+        }
+        if lost {
+            self.update_kad_mode();
+        }
+    }
+
+    /// Adds the peers listen addrs to the DHT if they speak kademlia
+    pub fn add_routing(&mut self, info: &Info) {
+        let remote_id = info.public_key.to_peer_id();
+        if remote_id == self.local_peer_id || !info.protocols.contains(&kad::PROTOCOL_NAME) {
+            return;
+        }
+
+        let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+            return;
+        };
+        for addr in &info.listen_addrs {
+            kad.add_address(&remote_id, addr.clone());
+        }
+    }
+
+    /// Records the result of an AutoNAT test, confirming the tested address
+    /// as external if it was reachable.
+    pub fn reachability(&mut self, event: autonat::v2::client::Event) {
+        let addr = event.tested_addr;
+        match event.result {
+            Ok(()) => {
+                self.reachable.insert(addr.clone());
+                // Start the relay first so that it sees this address get confirmed
+                if let Some(cfg) = self.pending_relay.take() {
+                    self.start_relay(cfg);
+                }
+                self.swarm.add_external_address(addr);
+            }
+            Err(_) => {
+                if self.reachable.remove(&addr) {
+                    self.swarm.remove_external_address(&addr);
+                }
+            }
+        }
+        self.update_kad_mode();
+    }
+
+    /// Forgets an address that is no longer external
+    pub fn address_expired(&mut self, addr: &Multiaddr) {
+        if self.reachable.remove(addr) {
+            self.update_kad_mode();
+        }
+    }
+
+    /// Drops confirmations of addresses the swarm no longer has as external,
+    /// in case we missed the event. Confirmations don't expire with time since
+    /// AutoNAT never tests an address twice, so they would never come back.
+    /// Should be called periodically.
+    pub fn expire_reachability(&mut self) {
+        let external: HashSet<&Multiaddr> = self.swarm.external_addresses().collect();
+        let before = self.reachable.len();
+        self.reachable.retain(|addr| {
+            let keep = external.contains(addr);
+            if !keep {
+                info!(target: "monitor", "{} is no longer external", addr);
+            }
+            keep
+        });
+        if self.reachable.len() != before {
+            self.update_kad_mode();
+        }
+    }
+
+    /// We can only be a DHT server if someone can actually reach us
+    fn update_kad_mode(&mut self) {
+        let mode = if self.reachable.is_empty() {
+            kad::Mode::Client
+        } else {
+            kad::Mode::Server
+        };
+        let Some(kad) = self.behaviour_mut().kad.as_mut() else {
+            return;
+        };
+        if kad.mode() != mode {
+            kad.set_mode(Some(mode));
+        }
+    }
+
+    pub fn swarm_mut(&mut self) -> &mut Swarm<MainBehaviour> {
+        &mut self.swarm
+    }
+
     pub fn behaviour_mut(&mut self) -> &mut MainBehaviour {
         self.swarm.behaviour_mut()
     }
@@ -135,3 +934,269 @@ impl ConnectionMonitor {
         self.rendezvous.iter()
     }
 }
+
+/// Every room gets its own namespace so peers only dial others in the same room
+pub fn room_namespace(room: &str) -> Result<Namespace, MonitorError> {
+    Namespace::new(format!("{}/{}", NAMESPACE, room))
+        .map_err(|_| MonitorError::InvalidRoom(room.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::Method;
+    use futures::StreamExt;
+    use libp2p::core::transport::{MemoryTransport, Transport, upgrade::Version};
+    use libp2p::{SwarmBuilder, noise, yamux};
+
+    fn memory_swarm<B: libp2p::swarm::NetworkBehaviour>(
+        behaviour: impl FnOnce(&libp2p::identity::Keypair, relay::client::Behaviour) -> B,
+    ) -> Swarm<B> {
+        SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|keys| {
+                MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(keys).unwrap())
+                    .multiplex(yamux::Config::default())
+            })
+            .unwrap()
+            .with_relay_client(noise::Config::new, yamux::Config::default)
+            .unwrap()
+            .with_behaviour(behaviour)
+            .unwrap()
+            .build()
+    }
+
+    fn node(methods: Vec<Method>) -> (ConnectionMonitor, Multiaddr) {
+        let swarm = memory_swarm(|keys, relay_client| {
+            let discovery = Discovery { discovery: methods };
+            MainBehaviour::new(keys, relay_client, None, &discovery, &[])
+        });
+        let mut monitor =
+            ConnectionMonitor::new(swarm, AddressBook::default(), Discovery::default());
+        let addr: Multiaddr = Protocol::Memory(rand::random::<u64>()).into();
+        monitor.swarm_mut().listen_on(addr.clone()).unwrap();
+        (monitor, addr)
+    }
+
+    fn relay_node() -> (ConnectionMonitor, Multiaddr) {
+        node(vec![])
+    }
+
+    fn confirmed(addr: &Multiaddr) -> autonat::v2::client::Event {
+        autonat::v2::client::Event {
+            tested_addr: addr.clone(),
+            bytes_sent: 0,
+            server: PeerId::random(),
+            result: Ok(()),
+        }
+    }
+
+    /// Asks `relay` for a reservation and returns the first circuit addr it
+    /// came with. Panics if the reservation is refused.
+    async fn reserve(mut relay: ConnectionMonitor, relay_addr: &Multiaddr) -> Multiaddr {
+        let mut client = memory_swarm(|_, relay_client| relay_client);
+        let relay_id = *relay.swarm_mut().local_peer_id();
+        let circuit = relay_addr
+            .clone()
+            .with(Protocol::P2p(relay_id))
+            .with(Protocol::P2pCircuit);
+        client.listen_on(circuit).unwrap();
+
+        let reservation = async {
+            loop {
+                tokio::select! {
+                    _ = relay.swarm_mut().select_next_some() => {}
+                    event = client.select_next_some() => match event {
+                        SwarmEvent::NewListenAddr { address, .. } => return address,
+                        SwarmEvent::ListenerClosed { reason, .. } => {
+                            panic!("Reservation refused: {:?}", reason)
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), reservation)
+            .await
+            .expect("No reservation within 10s")
+    }
+
+    #[tokio::test]
+    async fn relay_enabled_by_reachability_advertises_addrs() {
+        let (mut relay, addr) = relay_node();
+        relay.relay_when_reachable(relay::Config::default());
+        relay.reachability(confirmed(&addr));
+
+        let reserved = reserve(relay, &addr).await;
+        assert!(reserved.to_string().starts_with(&addr.to_string()));
+    }
+
+    #[tokio::test]
+    async fn relay_enabled_after_reachability_advertises_addrs() {
+        let (mut relay, addr) = relay_node();
+        relay.reachability(confirmed(&addr));
+        relay.relay_when_reachable(relay::Config::default());
+
+        let reserved = reserve(relay, &addr).await;
+        assert!(reserved.to_string().starts_with(&addr.to_string()));
+    }
+
+    #[tokio::test]
+    async fn confirmed_node_stays_dht_server() {
+        let (mut node, addr) = node(vec![Method::Kademlia]);
+        let mode = |node: &mut ConnectionMonitor| node.behaviour_mut().kad.as_mut().unwrap().mode();
+        assert_eq!(mode(&mut node), kad::Mode::Client);
+
+        node.reachability(confirmed(&addr));
+        for _ in 0..3 {
+            node.expire_reachability();
+        }
+        assert_eq!(mode(&mut node), kad::Mode::Server);
+
+        // Only losing the address demotes it
+        node.swarm_mut().remove_external_address(&addr);
+        node.expire_reachability();
+        assert_eq!(mode(&mut node), kad::Mode::Client);
+    }
+
+    #[test]
+    fn status_transitions() {
+        let addr: Multiaddr = Protocol::Memory(1).into();
+        let confirmed = Status::Confirmed(addr);
+        let allowed = [
+            (Status::Dialing, Status::Connected),
+            (Status::Connected, confirmed.clone()),
+            (confirmed.clone(), confirmed.clone()),
+            (Status::Dialing, Status::Closed),
+            (Status::Connected, Status::Closed),
+            (confirmed.clone(), Status::Closed),
+        ];
+        for (from, to) in &allowed {
+            assert!(from.can_become(to), "{:?} -> {:?}", from, to);
+        }
+
+        let rejected = [
+            (Status::Dialing, confirmed.clone()),
+            (Status::Dialing, Status::Dialing),
+            (Status::Connected, Status::Dialing),
+            (Status::Connected, Status::Connected),
+            (confirmed.clone(), Status::Connected),
+            (Status::Closed, Status::Connected),
+            (Status::Closed, Status::Closed),
+        ];
+        for (from, to) in &rejected {
+            assert!(!from.can_become(to), "{:?} -> {:?}", from, to);
+        }
+    }
+
+    fn established(
+        id: ConnectionId,
+        peer_id: PeerId,
+        endpoint: ConnectedPoint,
+    ) -> SwarmEvent<MainBehaviourEvent> {
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            connection_id: id,
+            endpoint,
+            num_established: std::num::NonZeroU32::new(1).unwrap(),
+            concurrent_dial_errors: None,
+            established_in: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn track_follows_the_lifecycle() {
+        let (mut monitor, addr) = relay_node();
+        let peer_id = PeerId::random();
+        let id = ConnectionId::new_unchecked(1);
+        let dialer = ConnectedPoint::Dialer {
+            address: addr.clone(),
+            role_override: libp2p::core::Endpoint::Dialer,
+            port_use: libp2p::core::transport::PortUse::Reuse,
+        };
+
+        monitor
+            .track(&SwarmEvent::Dialing {
+                peer_id: Some(peer_id),
+                connection_id: id,
+            })
+            .unwrap();
+        assert_eq!(monitor.connection(id).unwrap().status, Status::Dialing);
+        monitor
+            .track(&established(id, peer_id, dialer.clone()))
+            .unwrap();
+        assert_eq!(monitor.connection(id).unwrap().status, Status::Connected);
+        monitor
+            .update_status(id, Status::Confirmed(addr.clone()))
+            .unwrap();
+        assert!(matches!(
+            monitor.update_status(id, Status::Connected),
+            Err(MonitorError::InvalidTransition { .. })
+        ));
+
+        monitor
+            .track(&SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id: id,
+                endpoint: dialer,
+                num_established: 0,
+                cause: None,
+            })
+            .unwrap();
+        assert!(monitor.connection(id).is_none());
+        let closed = monitor.history().last().unwrap();
+        assert_eq!((closed.id, &closed.status), (id, &Status::Closed));
+        assert!(matches!(
+            monitor.update_status(id, Status::Connected),
+            Err(MonitorError::UnknownConnection(_))
+        ));
+
+        // Inbound connections show up without `Dialing`
+        let inbound = ConnectionId::new_unchecked(2);
+        let listener = ConnectedPoint::Listener {
+            local_addr: addr.clone(),
+            send_back_addr: addr,
+        };
+        monitor
+            .track(&established(inbound, peer_id, listener))
+            .unwrap();
+        assert_eq!(
+            monitor.connection(inbound).unwrap().status,
+            Status::Connected
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_dials_are_closed() {
+        let (mut monitor, _) = relay_node();
+        let id = ConnectionId::new_unchecked(1);
+        monitor
+            .track(&SwarmEvent::Dialing {
+                peer_id: None,
+                connection_id: id,
+            })
+            .unwrap();
+        monitor
+            .track(&SwarmEvent::OutgoingConnectionError {
+                connection_id: id,
+                peer_id: None,
+                error: DialError::Aborted,
+            })
+            .unwrap();
+        let closed = monitor.history().last().unwrap();
+        assert_eq!(closed.status, Status::Closed);
+        assert!(closed.cause.is_some());
+
+        // A dial that never got to `Dialing` isn't an error
+        monitor
+            .track(&SwarmEvent::OutgoingConnectionError {
+                connection_id: ConnectionId::new_unchecked(2),
+                peer_id: Some(PeerId::random()),
+                error: DialError::NoAddresses,
+            })
+            .unwrap();
+        assert_eq!(monitor.history().count(), 1);
+    }
+}
@@ -0,0 +1,98 @@
+//! Persistent node identities. Every binary used to call `Keypair::generate_ed25519()`
+//! on start which gave us a new PeerId on every restart, breaking any multiaddr or
+//! rendezvous registration we handed out.
+//!
+//! Keys are stored as the protobuf encoding used by libp2p (the same format kubo uses).
+//! Only ed25519 keys can be created here since libp2p can't generate RSA keys, but an
+//! existing RSA key can be loaded either as protobuf or as a PKCS#8 DER file.
+use libp2p::identity::{DecodingError, Keypair};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Decode(DecodingError),
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "Could not access keyfile: {}", e),
+            KeystoreError::Decode(e) => write!(f, "Could not decode keyfile: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<DecodingError> for KeystoreError {
+    fn from(e: DecodingError) -> Self {
+        KeystoreError::Decode(e)
+    }
+}
+
+/// Reads a keypair from `path`. Protobuf is tried first and if that fails we
+/// fall back to a PKCS#8 encoded RSA key.
+pub fn load(path: &Path) -> Result<Keypair, KeystoreError> {
+    let mut bytes = fs::read(path)?;
+
+    match Keypair::from_protobuf_encoding(&bytes) {
+        Ok(keys) => Ok(keys),
+        Err(e) => Keypair::rsa_from_pkcs8(&mut bytes).map_err(|_| e.into()),
+    }
+}
+
+/// Writes `keys` to a new file at `path` that only the owner can read.
+/// This will not overwrite an existing file.
+pub fn save(path: &Path, keys: &Keypair) -> Result<(), KeystoreError> {
+    let bytes = keys.to_protobuf_encoding()?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Loads the keypair at `path`, creating a new ed25519 keypair there on first run.
+pub fn load_or_generate(path: &Path) -> Result<Keypair, KeystoreError> {
+    if path.exists() {
+        let keys = load(path)?;
+        info!(target: "keystore", "Loaded {:?} identity {} from {}", keys.key_type(), keys.public().to_peer_id(), path.display());
+        return Ok(keys);
+    }
+
+    let keys = Keypair::generate_ed25519();
+    save(path, &keys)?;
+    info!(target: "keystore", "Created identity {} at {}", keys.public().to_peer_id(), path.display());
+    Ok(keys)
+}
+
+/// Helper for the binaries: uses the keyfile if one was given, otherwise an
+/// ephemeral keypair is made like we used to.
+pub fn identity(path: Option<&Path>) -> Result<Keypair, KeystoreError> {
+    match path {
+        Some(path) => load_or_generate(path),
+        None => {
+            warn!(target: "keystore", "No identity file given, using a temporary keypair");
+            Ok(Keypair::generate_ed25519())
+        }
+    }
+}
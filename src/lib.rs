@@ -7,6 +7,13 @@ pub const BANNER: &str = r#"
                  |___/       |_|        |_|    
 "#;
 
+pub mod addressbook;
 pub mod behaviour;
+pub mod discovery;
 pub mod events;
+pub mod keystore;
+pub mod registry;
+pub mod relaylimits;
+pub mod rendezvousserver;
 pub mod socket;
+pub mod unixsocket;
@@ -1,36 +1,32 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser, error::ErrorKind};
 use futures::prelude::*;
-use libp2p::swarm::{self, ConnectionId, Swarm, SwarmEvent, dial_opts::DialOpts};
-use libp2p::{Multiaddr, SwarmBuilder, gossipsub, identity::Keypair, noise, tcp, yamux};
+use libp2p::swarm::SwarmEvent;
+use libp2p::{Multiaddr, SwarmBuilder, gossipsub, noise, tcp, yamux};
 use magicp2p::{
     self,
-    behaviour::{MainBehaviour, MainBehaviourEvent, SwarmOpts},
-    events::{ConnectionMonitor, Status},
+    addressbook::AddressBook,
+    behaviour::{self, BOOTNODES, MainBehaviour, MainBehaviourEvent, SwarmOpts},
+    discovery::Discovery,
+    events::ConnectionMonitor,
+    keystore,
+    relaylimits::{RelayLimits, RelayMode},
 };
 use std::error::Error;
+use std::path::PathBuf;
 use tokio::{
     self, io,
     io::AsyncBufReadExt,
-    select,
+    select, signal,
     time::{self, Duration},
 };
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-fn dial_remote(
-    swarm: &mut Swarm<MainBehaviour>,
-    addr: &Multiaddr,
-) -> Result<ConnectionId, swarm::DialError> {
-    let dialer = DialOpts::unknown_peer_id().address(addr.clone()).build();
-    let id = dialer.connection_id();
-    swarm.dial(dialer)?;
-
-    swarm.add_external_address(addr.clone());
-
-    Ok(id)
-}
-
 fn network_handle(monitor: &mut ConnectionMonitor, event: SwarmEvent<MainBehaviourEvent>) {
+    if let Err(e) = monitor.track(&event) {
+        warn!("{}", e);
+    }
+
     match event {
         SwarmEvent::Dialing { peer_id, .. } => {
             info!("Dialing {:?}", peer_id);
@@ -41,8 +37,34 @@ fn network_handle(monitor: &mut ConnectionMonitor, event: SwarmEvent<MainBehavio
         SwarmEvent::ExternalAddrConfirmed { address } => {
             info!("External address confirmed: {address}");
         }
-        SwarmEvent::ConnectionEstablished { established_in, .. } => {
-            info!("Connected to server in {}ms", established_in.as_millis());
+        SwarmEvent::ExternalAddrExpired { address } => {
+            info!("External address expired: {address}");
+            monitor.address_expired(&address);
+        }
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            connection_id,
+            established_in,
+            ..
+        } => {
+            info!(
+                "Connected to <{}> in {}ms ({:?})",
+                peer_id,
+                established_in.as_millis(),
+                monitor.route(connection_id)
+            );
+        }
+        SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+            if let Some(err) = cause {
+                warn!("Connection to <{}> closed: {}", peer_id, err);
+            }
+        }
+        SwarmEvent::ListenerClosed {
+            listener_id,
+            addresses,
+            ..
+        } => {
+            monitor.listener_closed(listener_id, &addresses);
         }
         SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
             if let Some(id) = peer_id {
@@ -58,17 +80,32 @@ fn network_handle(monitor: &mut ConnectionMonitor, event: SwarmEvent<MainBehavio
             };
 
             match opt {
-                SwarmOpts::Connect(list) => {
-                    for peer in list {
-                        let record = peer.record;
-
-                        let dial_request = DialOpts::peer_id(record.peer_id())
-                            .addresses(record.addresses().to_vec())
-                            .build();
-                        monitor.dial(dial_request);
-                    }
-                }
+                SwarmOpts::Autonat(event) => monitor.reachability(event),
+                SwarmOpts::HolePunch(event) => monitor.hole_punch(event),
+                SwarmOpts::Reservation(relay) => monitor.reservation_accepted(relay),
+                SwarmOpts::Relay(event) => monitor.relay_event(&event),
+                SwarmOpts::Registered {
+                    rendezvous_node,
+                    namespace,
+                    ttl,
+                } => monitor.registered(rendezvous_node, namespace, ttl),
+                SwarmOpts::RegisterFailed {
+                    rendezvous_node,
+                    namespace,
+                } => monitor.register_failed(rendezvous_node, namespace),
+                SwarmOpts::Connect {
+                    rendezvous_node,
+                    registrations,
+                    cookie,
+                } => monitor.discovered(rendezvous_node, registrations, cookie),
+                SwarmOpts::DiscoverFailed {
+                    rendezvous_node,
+                    namespace,
+                } => monitor.discover_failed(rendezvous_node, namespace),
                 SwarmOpts::Identify(info) => {
+                    monitor.remember(info.public_key.to_peer_id(), info.listen_addrs.clone());
+                    monitor.add_routing(&info);
+                    monitor.reserve(&info);
                     monitor.regester(&info);
                 }
                 SwarmOpts::Message(message) => {
@@ -79,35 +116,84 @@ fn network_handle(monitor: &mut ConnectionMonitor, event: SwarmEvent<MainBehavio
                         String::from_utf8_lossy(&message.data)
                     );
                 }
-                SwarmOpts::Mdns(list) => {
-                    for dail in list {
-                        /*if swarm.is_connected(&dail.get_peer_id().unwrap()) {
-                            continue;
-                        }
-
-                        if let Err(err) = swarm.dial(dail) {
-                            error!("Problem dialing peer: {}", err);
-                        }*/
-                    }
-                }
+                SwarmOpts::Mdns(list) => monitor.mdns_discovered(list),
+                SwarmOpts::MdnsExpired(list) => monitor.mdns_expired(list),
             }
         }
         event => debug!("{:?}", event),
     }
 }
 
+const LISTEN_ADDRS: [&str; 4] = [
+    "/ip4/0.0.0.0/tcp/0",
+    "/ip4/0.0.0.0/udp/0/quic-v1",
+    "/ip6/::/tcp/0",
+    "/ip6/::/udp/0/quic-v1",
+];
+
+/// `/join <room>` and `/part <room>` switch rooms, anything else is sent to
+/// the last room joined.
+fn input_handle(monitor: &mut ConnectionMonitor, line: String, room: &mut Option<String>) {
+    if let Some(name) = line.strip_prefix("/join ") {
+        match monitor.join(name) {
+            Ok(()) => *room = Some(name.to_string()),
+            Err(e) => warn!("{}", e),
+        }
+        return;
+    }
+    if let Some(name) = line.strip_prefix("/part ") {
+        if let Err(e) = monitor.part(name) {
+            warn!("{}", e);
+        }
+        if room.as_deref() == Some(name) {
+            *room = None;
+        }
+        return;
+    }
+
+    let Some(name) = room else {
+        warn!("Not in a room, use /join <room>");
+        return;
+    };
+    let topic = gossipsub::IdentTopic::new(name.as_str());
+    if let Err(e) = monitor
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic, line.as_bytes())
+    {
+        warn!("Publish error: {e:?}");
+    }
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Opt {
-    /// Connects client to the server at the provided multiaddr. Starts in server mode if none is given.
+    /// Server to connect to and keep reconnecting to. The `/p2p` part can be left out.
     #[arg(short, long, value_name = "multiaddr")]
-    bootnode: Option<String>,
-    /// multiaddr that the client will be listening on.
-    #[arg(short, long, value_name = "multiaddr", default_value = "/ip6/::1")]
-    address: Vec<String>,
-    /// Disables mDNS
-    #[arg(short, long)]
-    mdns: bool,
+    bootnode: Option<Multiaddr>,
+    /// multiaddrs that the client will be listening on. Defaults to TCP and QUIC on both IPv4 and IPv6,
+    /// skipping whichever this host doesn't support.
+    #[arg(short, long, value_name = "multiaddr")]
+    address: Vec<Multiaddr>,
+    /// DHT peers to bootstrap from, defaults to the public Amino bootnodes.
+    #[arg(short, long, value_name = "multiaddr", default_values = BOOTNODES, hide_default_value = true)]
+    kad_bootnode: Vec<Multiaddr>,
+    /// Rooms to join on start. Peers are only discovered through rendezvous if they share a room.
+    #[arg(short, long, value_name = "name", default_value = "hi-dave")]
+    room: Vec<String>,
+    /// File where known peers are saved on shutdown and loaded from on start.
+    #[arg(short, long, value_name = "path")]
+    peers: Option<PathBuf>,
+    /// Keyfile for the node identity, created if it doesn't exist.
+    #[arg(short, long, value_name = "path")]
+    identity: Option<PathBuf>,
+    /// Whether to relay traffic for peers that can't be reached directly.
+    #[arg(long, value_name = "mode", default_value = "auto")]
+    relay_server: RelayMode,
+    #[command(flatten)]
+    discovery: Discovery,
+    #[command(flatten)]
+    relay: RelayLimits,
 }
 
 #[tokio::main]
@@ -118,57 +204,92 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .init();
 
     let args: Opt = Opt::parse();
+    if args.bootnode.is_some() && !args.discovery.bootnodes() {
+        Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--bootnode needs `bootnodes` in --discovery",
+            )
+            .exit();
+    }
 
-    let keys = Keypair::generate_ed25519();
-    let mut swarm = SwarmBuilder::with_existing_identity(keys.clone())
+    let keys = keystore::identity(args.identity.as_deref())?;
+    let mut swarm = SwarmBuilder::with_existing_identity(keys)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
             noise::Config::new,
             yamux::Config::default,
         )?
+        .with_quic()
         .with_dns()?
-        .with_behaviour(|keys| MainBehaviour::new(keys, !args.mdns))?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|keys, relay_client| {
+            MainBehaviour::new(
+                keys,
+                relay_client,
+                (args.relay_server == RelayMode::On).then(|| args.relay.config()),
+                &args.discovery,
+                &args.kad_bootnode,
+            )
+        })?
         .build();
 
-    for addr in args.address {
-        swarm.listen_on(format!("{}/tcp/0", addr).parse()?)?;
-        // swarm.listen_on(format!("{}/udp/0/quic-v1", addr).parse()?)?;
+    behaviour::listen(&mut swarm, args.address, &LISTEN_ADDRS)?;
+    let book = match args.peers {
+        Some(path) => AddressBook::open(path)?,
+        None => AddressBook::default(),
+    };
+    let mut monitor = ConnectionMonitor::new(swarm, book, args.discovery.clone());
+    if args.relay_server == RelayMode::Auto {
+        monitor.relay_when_reachable(args.relay.config());
     }
-    let mut monitor = ConnectionMonitor::new(swarm);
+    monitor.reconnect_known();
 
     if let Some(bootnode) = args.bootnode {
-        let bootnode: Multiaddr = bootnode.parse()?;
-        let request = DialOpts::unknown_peer_id().address(bootnode).build();
         // NOTE: We haven't confiremd the addr yet, we will do that later
-        monitor.dial(request);
+        monitor.dial_bootnode(bootnode);
     }
 
     let mut stdin = io::BufReader::new(io::stdin()).lines();
 
-    let topic = gossipsub::IdentTopic::new("hi-dave");
-    // swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    for room in &args.room {
+        monitor.join(room)?;
+    }
+    // Where lines from stdin are sent
+    let mut room = args.room.last().cloned();
 
     let mut discover = time::interval(Duration::from_secs(5));
+    let mut expire = time::interval(Duration::from_secs(60));
+    let mut redial = time::interval(Duration::from_secs(1));
+    let mut save = time::interval(Duration::from_secs(5 * 60));
     print!("{}", magicp2p::BANNER);
 
     loop {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    warn!("Publish error: {e:?}");
-                }
-            }
-            event = swarm.select_next_some() => network_handle(&mut monitor, event),
-            _ = discover.tick() => {
-                for &server in monitor.get_rendezvous() {
-                    info!(?server);
-                    info!("Scanning: {}", server);
-                    swarm.behaviour_mut().rendezvous.discover(None, None, None, server);
-                }
+            Ok(Some(line)) = stdin.next_line() => input_handle(&mut monitor, line, &mut room),
+            event = monitor.swarm_mut().select_next_some() => network_handle(&mut monitor, event),
+            _ = expire.tick() => monitor.expire_reachability(),
+            _ = redial.tick() => {
+                monitor.redial();
+                monitor.refresh_registrations();
             }
+            _ = discover.tick() => monitor.discover(),
+            _ = save.tick() => monitor.save_peers(),
+            _ = signal::ctrl_c() => break,
         }
     }
+
+    monitor.unregister_all();
+    // Give the unregister requests a chance to go out
+    let _ = time::timeout(Duration::from_secs(1), async {
+        loop {
+            let event = monitor.swarm_mut().select_next_some().await;
+            network_handle(&mut monitor, event);
+        }
+    })
+    .await;
+
+    monitor.save_peers();
+    Ok(())
 }
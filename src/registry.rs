@@ -0,0 +1,398 @@
+//! Registrations held by the rendezvous server, see [`crate::rendezvousserver`].
+//!
+//! The caps are checked here before a registration is accepted so a full
+//! namespace turns newcomers away instead of serving them. Registrations can
+//! be snapshot to disk and are served again after a restart.
+use libp2p::PeerId;
+use libp2p::core::{PeerRecord, SignedEnvelope};
+use libp2p::rendezvous::{self, Cookie, ErrorCode, Namespace, Registration};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Cookies are forgotten after this long, a client holding one just gets
+/// everything again
+const COOKIE_TTL: Duration = Duration::from_secs(rendezvous::MAX_TTL);
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Peer { peer: PeerId, limit: usize },
+    Namespace { namespace: Namespace, limit: usize },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Peer { peer, limit } => {
+                write!(f, "<{}> already has {} registrations", peer, limit)
+            }
+            LimitExceeded::Namespace { namespace, limit } => {
+                write!(f, "{} already has {} registrations", namespace, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// On disk format
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    namespace: String,
+    /// Protobuf encoded signed envelope of the peer record
+    envelope: Vec<u8>,
+    /// Seconds since the unix epoch
+    expires: u64,
+}
+
+struct Stored {
+    record: PeerRecord,
+    expires: SystemTime,
+    /// Position in the order registrations came in, see `Registry::discover`
+    seq: u64,
+}
+
+pub struct Registry {
+    /// Where the snapshot is saved, `None` keeps it in memory only
+    path: Option<PathBuf>,
+    max_per_peer: usize,
+    max_per_namespace: usize,
+    registrations: HashMap<(PeerId, Namespace), Stored>,
+    /// Bumped for every registration, including renewals
+    seq: u64,
+    /// The last `seq` each cookie has seen and when it was handed out
+    cookies: HashMap<Cookie, (u64, SystemTime)>,
+}
+
+impl Registry {
+    pub fn new(max_per_peer: usize, max_per_namespace: usize) -> Self {
+        Registry {
+            path: None,
+            max_per_peer,
+            max_per_namespace,
+            registrations: HashMap::new(),
+            seq: 0,
+            cookies: HashMap::new(),
+        }
+    }
+
+    /// Loads the snapshot saved at `path`, skipping anything that expired
+    /// while we were down. Nothing is loaded if the file doesn't exist yet.
+    pub fn open(mut self, path: PathBuf) -> io::Result<Self> {
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let entries: Vec<Entry> = serde_json::from_slice(&bytes)?;
+                let now = SystemTime::now();
+                for entry in entries {
+                    let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(entry.expires);
+                    if expires <= now {
+                        continue;
+                    }
+                    let record = SignedEnvelope::from_protobuf_encoding(&entry.envelope)
+                        .ok()
+                        .and_then(|e| PeerRecord::from_signed_envelope(e).ok());
+                    let (Some(record), Ok(namespace)) = (record, Namespace::new(entry.namespace))
+                    else {
+                        warn!(target: "registry", "Skipping bad record in {}", path.display());
+                        continue;
+                    };
+                    self.seq += 1;
+                    self.registrations.insert(
+                        (record.peer_id(), namespace),
+                        Stored {
+                            record,
+                            expires,
+                            seq: self.seq,
+                        },
+                    );
+                }
+                info!(target: "registry", "Loaded {} registrations from {}", self.registrations.len(), path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        self.path = Some(path);
+        Ok(self)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let now = SystemTime::now();
+        let entries: Vec<Entry> = self
+            .registrations
+            .iter()
+            .filter(|(_, reg)| reg.expires > now)
+            .map(|((_, namespace), reg)| Entry {
+                namespace: namespace.to_string(),
+                envelope: reg.record.to_signed_envelope().into_protobuf_encoding(),
+                expires: reg
+                    .expires
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+
+        // Write then rename so a crash can't leave us with half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&entries)?)?;
+        fs::rename(&tmp, path)?;
+        info!(target: "registry", "Saved {} registrations to {}", entries.len(), path.display());
+        Ok(())
+    }
+
+    /// Accepts `registration` from `peer` unless it would go over the caps.
+    /// Renewing a registration in the same namespace replaces the old one and
+    /// doesn't count against them.
+    pub fn add(&mut self, peer: PeerId, registration: Registration) -> Result<(), LimitExceeded> {
+        let now = SystemTime::now();
+        let key = (peer, registration.namespace);
+        let live = |reg: &Stored| reg.expires > now;
+
+        if !self.registrations.get(&key).is_some_and(live) {
+            let per_peer = self
+                .registrations
+                .iter()
+                .filter(|((p, _), reg)| *p == peer && live(reg))
+                .count();
+            if per_peer >= self.max_per_peer {
+                return Err(LimitExceeded::Peer {
+                    peer,
+                    limit: self.max_per_peer,
+                });
+            }
+
+            let per_namespace = self
+                .registrations
+                .iter()
+                .filter(|((_, n), reg)| *n == key.1 && live(reg))
+                .count();
+            if per_namespace >= self.max_per_namespace {
+                return Err(LimitExceeded::Namespace {
+                    namespace: key.1,
+                    limit: self.max_per_namespace,
+                });
+            }
+        }
+
+        self.seq += 1;
+        self.registrations.insert(
+            key,
+            Stored {
+                record: registration.record,
+                expires: now + Duration::from_secs(registration.ttl),
+                seq: self.seq,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn remove(&mut self, peer: PeerId, namespace: &Namespace) {
+        self.registrations.remove(&(peer, namespace.clone()));
+    }
+
+    /// Registrations in `namespace`, or in every namespace if it's `None`,
+    /// that came in after `cookie` was handed out. At most `limit` are
+    /// returned along with a cookie to continue from.
+    pub fn discover(
+        &mut self,
+        namespace: Option<&Namespace>,
+        cookie: Option<Cookie>,
+        limit: Option<u64>,
+    ) -> Result<(Vec<Registration>, Cookie), ErrorCode> {
+        if cookie.as_ref().is_some_and(|c| c.namespace() != namespace) {
+            return Err(ErrorCode::InvalidCookie);
+        }
+
+        let now = SystemTime::now();
+        // A cookie is only good once, the client continues from the one we
+        // hand back. An unknown cookie starts over, we may have been
+        // restarted since.
+        let seen = cookie
+            .and_then(|c| self.cookies.remove(&c))
+            .map_or(0, |(seq, _)| seq);
+        let mut found: Vec<_> = self
+            .registrations
+            .iter()
+            .filter(|((_, n), reg)| {
+                namespace.is_none_or(|ns| ns == n) && reg.seq > seen && reg.expires > now
+            })
+            .collect();
+        found.sort_by_key(|(_, reg)| reg.seq);
+        if let Some(limit) = limit {
+            found.truncate(limit as usize);
+        }
+
+        let last = found.last().map_or(seen, |(_, reg)| reg.seq);
+        let registrations = found
+            .into_iter()
+            .map(|((_, namespace), reg)| Registration {
+                namespace: namespace.clone(),
+                record: reg.record.clone(),
+                ttl: reg
+                    .expires
+                    .duration_since(now)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+
+        let cookie = match namespace {
+            Some(ns) => Cookie::for_namespace(ns.clone()),
+            None => Cookie::for_all_namespaces(),
+        };
+        self.cookies.insert(cookie.clone(), (last, now));
+        Ok((registrations, cookie))
+    }
+
+    /// Drops everything past its TTL and returns the registrations that
+    /// expired. Should be called periodically.
+    pub fn expire(&mut self) -> Vec<Registration> {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+        self.registrations.retain(|(_, namespace), reg| {
+            let keep = reg.expires > now;
+            if !keep {
+                expired.push(Registration {
+                    namespace: namespace.clone(),
+                    record: reg.record.clone(),
+                    ttl: 0,
+                });
+            }
+            keep
+        });
+        // A cookie that hasn't seen any live registration gives the same
+        // answer as none at all
+        let oldest = self.registrations.values().map(|reg| reg.seq).min();
+        self.cookies.retain(|_, (seq, issued)| {
+            oldest.is_some_and(|oldest| *seq >= oldest)
+                && now.duration_since(*issued).unwrap_or_default() < COOKIE_TTL
+        });
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.registrations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registrations.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn registration(keys: &Keypair, namespace: &str) -> Registration {
+        let addr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        Registration {
+            namespace: Namespace::new(namespace.to_string()).unwrap(),
+            record: PeerRecord::new(keys, vec![addr]).unwrap(),
+            ttl: rendezvous::DEFAULT_TTL,
+        }
+    }
+
+    #[test]
+    fn full_namespace_refuses_newcomers() {
+        let mut registry = Registry::new(8, 1);
+        let first = Keypair::generate_ed25519();
+        let second = Keypair::generate_ed25519();
+        let ns = Namespace::from_static("room");
+
+        registry
+            .add(first.public().to_peer_id(), registration(&first, "room"))
+            .unwrap();
+        assert!(matches!(
+            registry.add(second.public().to_peer_id(), registration(&second, "room")),
+            Err(LimitExceeded::Namespace { .. })
+        ));
+        // Renewing doesn't count against the cap
+        registry
+            .add(first.public().to_peer_id(), registration(&first, "room"))
+            .unwrap();
+
+        let (found, _) = registry.discover(Some(&ns), None, None).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.peer_id(), first.public().to_peer_id());
+    }
+
+    #[test]
+    fn cookie_only_returns_new_registrations() {
+        let mut registry = Registry::new(8, 8);
+        let first = Keypair::generate_ed25519();
+        let second = Keypair::generate_ed25519();
+        let ns = Namespace::from_static("room");
+
+        registry
+            .add(first.public().to_peer_id(), registration(&first, "room"))
+            .unwrap();
+        let (found, mut cookie) = registry.discover(Some(&ns), None, None).unwrap();
+        assert_eq!(found.len(), 1);
+        for _ in 0..10 {
+            let (found, next) = registry.discover(Some(&ns), Some(cookie), None).unwrap();
+            assert!(found.is_empty());
+            cookie = next;
+        }
+
+        registry
+            .add(second.public().to_peer_id(), registration(&second, "room"))
+            .unwrap();
+        let (found, _) = registry.discover(Some(&ns), Some(cookie), None).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.peer_id(), second.public().to_peer_id());
+
+        // Only the cookie handed out last is kept
+        assert_eq!(registry.cookies.len(), 1);
+
+        let other = Cookie::for_namespace(Namespace::from_static("other"));
+        assert_eq!(
+            registry.discover(Some(&ns), Some(other), None).unwrap_err(),
+            ErrorCode::InvalidCookie
+        );
+    }
+
+    #[test]
+    fn snapshot_is_served_after_restart() {
+        let path = std::env::temp_dir().join(format!("registry-{}.json", rand::random::<u64>()));
+        let keys = Keypair::generate_ed25519();
+
+        let mut registry = Registry::new(8, 8).open(path.clone()).unwrap();
+        registry
+            .add(keys.public().to_peer_id(), registration(&keys, "room"))
+            .unwrap();
+        registry.save().unwrap();
+
+        let mut restored = Registry::new(8, 8).open(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (found, _) = restored.discover(None, None, None).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.peer_id(), keys.public().to_peer_id());
+        assert_eq!(found[0].namespace, Namespace::from_static("room"));
+    }
+
+    #[test]
+    fn cookies_go_with_their_registrations() {
+        let mut registry = Registry::new(8, 8);
+        let keys = Keypair::generate_ed25519();
+        let mut reg = registration(&keys, "room");
+        reg.ttl = 0;
+        registry.add(keys.public().to_peer_id(), reg).unwrap();
+        registry.discover(None, None, None).unwrap();
+        assert_eq!(registry.cookies.len(), 1);
+
+        assert_eq!(registry.expire().len(), 1);
+        assert!(registry.cookies.is_empty());
+    }
+}
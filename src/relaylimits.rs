@@ -0,0 +1,142 @@
+//! Limits for the circuit relay server run by both the nodes and the server
+//! binary. Anyone can ask us for a reservation, so the defaults are kept
+//! close to libp2p's and can be tightened from the command line.
+use clap::{Args, Command, FromArgMatches, ValueEnum};
+use libp2p::{Multiaddr, PeerId, relay};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// When a node runs a relay server for others
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug, Default)]
+pub enum RelayMode {
+    /// Once AutoNAT confirms one of our addresses
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RelayLimits {
+    /// Reservations the relay holds at once.
+    #[arg(long, value_name = "count", default_value_t = 128)]
+    pub max_reservations: usize,
+    /// Relayed circuits open at once.
+    #[arg(long, value_name = "count", default_value_t = 16)]
+    pub max_circuits: usize,
+    /// Relayed circuits a single peer may have open at once.
+    #[arg(long, value_name = "count", default_value_t = 4)]
+    pub max_circuits_per_peer: usize,
+    /// Seconds before a relayed circuit is closed.
+    #[arg(long, value_name = "seconds", default_value_t = 2 * 60)]
+    pub max_circuit_duration: u64,
+    /// Bytes relayed in each direction before a circuit is closed.
+    #[arg(long, value_name = "bytes", default_value_t = 1 << 17)]
+    pub max_circuit_bytes: u64,
+    /// Only these peers may reserve a slot on our relay. Anyone may if none are given.
+    #[arg(long, value_name = "peer id")]
+    pub relay_allow: Vec<PeerId>,
+}
+
+/// The clap defaults, so they are only written down once
+impl Default for RelayLimits {
+    fn default() -> Self {
+        let matches = RelayLimits::augment_args(Command::new("relay")).get_matches_from(["relay"]);
+        RelayLimits::from_arg_matches(&matches).expect("Defaults always parse")
+    }
+}
+
+impl RelayLimits {
+    pub fn config(&self) -> relay::Config {
+        let mut cfg = relay::Config {
+            max_reservations: self.max_reservations,
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration),
+            max_circuit_bytes: self.max_circuit_bytes,
+            ..Default::default()
+        };
+
+        if !self.relay_allow.is_empty() {
+            let allowed = AllowList(self.relay_allow.iter().copied().collect());
+            cfg.reservation_rate_limiters.push(Box::new(allowed));
+        }
+        cfg
+    }
+}
+
+/// Denies reservations from anyone not on the list. Plugged in as a rate
+/// limiter since that's the only hook the relay gives us before accepting.
+struct AllowList(HashSet<PeerId>);
+
+impl relay::RateLimiter for AllowList {
+    fn try_next(&mut self, peer: PeerId, _addr: &Multiaddr, _now: Instant) -> bool {
+        self.0.contains(&peer)
+    }
+}
+
+/// Running totals of what the relay server did, logged with every event
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RelayStats {
+    pub reservations: usize,
+    pub reservations_denied: usize,
+    pub circuits: usize,
+    pub circuits_denied: usize,
+    pub circuits_closed: usize,
+}
+
+impl RelayStats {
+    pub fn active_circuits(&self) -> usize {
+        self.circuits.saturating_sub(self.circuits_closed)
+    }
+
+    pub fn observe(&mut self, event: &relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            } => {
+                if !renewed {
+                    self.reservations += 1;
+                }
+                info!(target: "relay", peer = %src_peer_id, renewed, total = self.reservations, "Reservation accepted");
+            }
+            relay::Event::ReservationReqDenied {
+                src_peer_id,
+                status,
+            } => {
+                self.reservations_denied += 1;
+                info!(target: "relay", peer = %src_peer_id, ?status, denied = self.reservations_denied, "Reservation denied");
+            }
+            relay::Event::ReservationTimedOut { src_peer_id }
+            | relay::Event::ReservationClosed { src_peer_id } => {
+                info!(target: "relay", peer = %src_peer_id, "Reservation ended");
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                self.circuits += 1;
+                info!(target: "relay", src = %src_peer_id, dst = %dst_peer_id, active = self.active_circuits(), "Circuit opened");
+            }
+            relay::Event::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+                status,
+            } => {
+                self.circuits_denied += 1;
+                info!(target: "relay", src = %src_peer_id, dst = %dst_peer_id, ?status, denied = self.circuits_denied, "Circuit denied");
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                self.circuits_closed += 1;
+                info!(target: "relay", src = %src_peer_id, dst = %dst_peer_id, ?error, active = self.active_circuits(), "Circuit closed");
+            }
+            e => debug!(target: "relay", "{:?}", e),
+        }
+    }
+}
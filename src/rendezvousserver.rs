@@ -0,0 +1,828 @@
+//! The server side of the rendezvous protocol.
+//!
+//! libp2p's server accepts every registration within its TTL bounds and
+//! keeps them to itself, so there is no way to check our caps before a
+//! registration is served or to hand it a restored snapshot. This speaks the
+//! same wire format on top of request-response and keeps everything in a
+//! [`Registry`] instead. Clients keep using `rendezvous::client`.
+use crate::registry::Registry;
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::core::{Endpoint, PeerRecord, SignedEnvelope, transport::PortUse};
+use libp2p::rendezvous::{self, Cookie, ErrorCode, Namespace, Registration, Ttl, server::Event};
+use libp2p::request_response::{self, ProtocolSupport, ResponseChannel};
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use std::collections::VecDeque;
+use std::io;
+use std::iter;
+use std::task::{Context, Poll};
+use tracing::{debug, warn};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/rendezvous/1.0.0");
+/// Same limit libp2p uses for rendezvous messages
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// `Message.MessageType` in rendezvous.proto
+const REGISTER: u64 = 0;
+const REGISTER_RESPONSE: u64 = 1;
+const UNREGISTER: u64 = 2;
+const DISCOVER: u64 = 3;
+const DISCOVER_RESPONSE: u64 = 4;
+
+#[derive(Debug)]
+pub enum Request {
+    Register {
+        namespace: Namespace,
+        record: Box<PeerRecord>,
+        ttl: Option<Ttl>,
+    },
+    Unregister(Namespace),
+    Discover {
+        namespace: Option<Namespace>,
+        cookie: Option<Cookie>,
+        limit: Option<u64>,
+    },
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Register(Result<Ttl, ErrorCode>),
+    Discover(Result<(Vec<Registration>, Cookie), ErrorCode>),
+}
+
+pub struct Behaviour {
+    inner: request_response::Behaviour<Codec>,
+    registry: Registry,
+    min_ttl: Ttl,
+    max_ttl: Ttl,
+    events: VecDeque<Event>,
+}
+
+impl Behaviour {
+    /// Registrations with a TTL outside of `min_ttl..=max_ttl` are refused
+    pub fn new(registry: Registry, min_ttl: Ttl, max_ttl: Ttl) -> Self {
+        let inner = request_response::Behaviour::with_codec(
+            Codec,
+            iter::once((PROTOCOL, ProtocolSupport::Inbound)),
+            request_response::Config::default(),
+        );
+        Behaviour {
+            inner,
+            registry,
+            min_ttl,
+            max_ttl,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Drops registrations past their TTL. Should be called periodically.
+    pub fn expire(&mut self) {
+        let expired = self.registry.expire();
+        self.events
+            .extend(expired.into_iter().map(Event::RegistrationExpired));
+    }
+
+    fn handle_request(
+        &mut self,
+        peer: PeerId,
+        request: Request,
+        channel: ResponseChannel<Response>,
+    ) {
+        let (event, response) = match request {
+            Request::Register {
+                namespace,
+                record,
+                ttl,
+            } => {
+                let ttl = ttl.unwrap_or(rendezvous::DEFAULT_TTL);
+                let registration = Registration {
+                    namespace: namespace.clone(),
+                    record: *record,
+                    ttl,
+                };
+                let result = if registration.record.peer_id() != peer {
+                    Err(ErrorCode::NotAuthorized)
+                } else if ttl < self.min_ttl || ttl > self.max_ttl {
+                    Err(ErrorCode::InvalidTtl)
+                } else {
+                    self.registry.add(peer, registration.clone()).map_err(|e| {
+                        warn!(target: "rendezvous", "Refusing <{}>: {}", peer, e);
+                        ErrorCode::Unavailable
+                    })
+                };
+                let event = match result {
+                    Ok(()) => Event::PeerRegistered { peer, registration },
+                    Err(error) => Event::PeerNotRegistered {
+                        peer,
+                        namespace,
+                        error,
+                    },
+                };
+                (event, Some(Response::Register(result.map(|()| ttl))))
+            }
+            // Like libp2p we don't answer these, the client doesn't wait for it
+            Request::Unregister(namespace) => {
+                self.registry.remove(peer, &namespace);
+                (Event::PeerUnregistered { peer, namespace }, None)
+            }
+            Request::Discover {
+                namespace,
+                cookie,
+                limit,
+            } => {
+                let result = self.registry.discover(namespace.as_ref(), cookie, limit);
+                let event = match &result {
+                    Ok((registrations, _)) => Event::DiscoverServed {
+                        enquirer: peer,
+                        registrations: registrations.clone(),
+                    },
+                    Err(error) => Event::DiscoverNotServed {
+                        enquirer: peer,
+                        error: *error,
+                    },
+                };
+                (event, Some(Response::Discover(result)))
+            }
+        };
+
+        if let Some(response) = response
+            && self.inner.send_response(channel, response).is_err()
+        {
+            debug!(target: "rendezvous", "<{}> went away before we could answer", peer);
+        }
+        self.events.push_back(event);
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler =
+        <request_response::Behaviour<Codec> as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+            port_use,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.inner.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(ToSwarm::GenerateEvent(event));
+            }
+
+            match self.inner.poll(cx) {
+                Poll::Ready(ToSwarm::GenerateEvent(request_response::Event::Message {
+                    peer,
+                    message:
+                        request_response::Message::Request {
+                            request, channel, ..
+                        },
+                    ..
+                })) => self.handle_request(peer, request, channel),
+                Poll::Ready(ToSwarm::GenerateEvent(request_response::Event::InboundFailure {
+                    peer,
+                    error,
+                    ..
+                })) => debug!(target: "rendezvous", "Request from <{}> failed: {}", peer, error),
+                Poll::Ready(ToSwarm::GenerateEvent(_)) => {}
+                Poll::Ready(other) => {
+                    return Poll::Ready(
+                        other.map_out(|_| unreachable!("events are handled above")),
+                    );
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Reads requests and writes responses, we never send requests ourselves
+#[derive(Clone, Default)]
+pub struct Codec;
+
+#[async_trait]
+impl request_response::Codec for Codec {
+    type Protocol = StreamProtocol;
+    type Request = Request;
+    type Response = Response;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = read_frame(io).await?;
+        decode_request(&message)
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, _: &mut T) -> io::Result<Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The server doesn't send requests",
+        ))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        _: &mut T,
+        _: Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The server doesn't send requests",
+        ))
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let message = encode_response(response);
+        let mut frame = Writer::default();
+        frame.varint(message.len() as u64);
+        frame.0.extend(message);
+        io.write_all(&frame.0).await?;
+        io.flush().await
+    }
+}
+
+/// Reads one varint length prefixed message
+async fn read_frame<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Vec<u8>> {
+    let mut len: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        io.read_exact(&mut byte).await?;
+        len |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            let len = usize::try_from(len).unwrap_or(usize::MAX);
+            if len > MAX_MESSAGE_LEN {
+                return Err(invalid("Message too long"));
+            }
+            let mut message = vec![0u8; len];
+            io.read_exact(&mut message).await?;
+            return Ok(message);
+        }
+    }
+    Err(invalid("Length prefix too long"))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// A protobuf field, fixed size fields are skipped since rendezvous.proto
+/// doesn't use them
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Iterates over the fields of a protobuf message
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value: u64 = 0;
+        for i in 0..10 {
+            let (&byte, rest) = self
+                .0
+                .split_first()
+                .ok_or_else(|| invalid("Truncated varint"))?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("Varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(invalid("Truncated field"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> io::Result<Option<(u64, Value<'a>)>> {
+        loop {
+            if self.0.is_empty() {
+                return Ok(None);
+            }
+            let key = self.varint()?;
+            let value = match key & 0x7 {
+                0 => Value::Varint(self.varint()?),
+                2 => {
+                    let len = self.varint()? as usize;
+                    Value::Bytes(self.take(len)?)
+                }
+                1 => {
+                    self.take(8)?;
+                    continue;
+                }
+                5 => {
+                    self.take(4)?;
+                    continue;
+                }
+                _ => return Err(invalid("Unknown wire type")),
+            };
+            return Ok(Some((key >> 3, value)));
+        }
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.field().transpose()
+    }
+}
+
+fn string(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("Invalid UTF-8"))
+}
+
+fn namespace(bytes: &[u8]) -> io::Result<Namespace> {
+    Namespace::new(string(bytes)?).map_err(|_| invalid("Namespace too long"))
+}
+
+fn decode_request(message: &[u8]) -> io::Result<Request> {
+    let mut kind = None;
+    let (mut register, mut unregister, mut discover) = (None, None, None);
+    for field in Fields(message) {
+        match field? {
+            (1, Value::Varint(v)) => kind = Some(v),
+            (2, Value::Bytes(b)) => register = Some(b),
+            (4, Value::Bytes(b)) => unregister = Some(b),
+            (5, Value::Bytes(b)) => discover = Some(b),
+            _ => {}
+        }
+    }
+
+    match (kind, register, unregister, discover) {
+        (Some(REGISTER), Some(register), _, _) => {
+            let (mut ns, mut record, mut ttl) = (None, None, None);
+            for field in Fields(register) {
+                match field? {
+                    (1, Value::Bytes(b)) => ns = Some(namespace(b)?),
+                    (2, Value::Bytes(b)) => record = Some(b),
+                    (3, Value::Varint(v)) => ttl = Some(v),
+                    _ => {}
+                }
+            }
+            let record = record.ok_or_else(|| invalid("Missing signed peer record"))?;
+            let record = SignedEnvelope::from_protobuf_encoding(record)
+                .ok()
+                .and_then(|e| PeerRecord::from_signed_envelope(e).ok())
+                .ok_or_else(|| invalid("Bad signed peer record"))?;
+            Ok(Request::Register {
+                namespace: ns.ok_or_else(|| invalid("Missing namespace"))?,
+                record: Box::new(record),
+                ttl,
+            })
+        }
+        (Some(UNREGISTER), _, Some(unregister), _) => {
+            let mut ns = None;
+            for field in Fields(unregister) {
+                if let (1, Value::Bytes(b)) = field? {
+                    ns = Some(namespace(b)?);
+                }
+            }
+            Ok(Request::Unregister(
+                ns.ok_or_else(|| invalid("Missing namespace"))?,
+            ))
+        }
+        (Some(DISCOVER), _, _, Some(discover)) => {
+            let (mut ns, mut limit, mut cookie) = (None, None, None);
+            for field in Fields(discover) {
+                match field? {
+                    (1, Value::Bytes(b)) => ns = Some(namespace(b)?),
+                    (2, Value::Varint(v)) => limit = Some(v),
+                    (3, Value::Bytes(b)) => {
+                        let c = Cookie::from_wire_encoding(b.to_vec())
+                            .map_err(|_| invalid("Bad cookie"))?;
+                        cookie = Some(c);
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Request::Discover {
+                namespace: ns,
+                cookie,
+                limit,
+            })
+        }
+        _ => Err(invalid("Not a rendezvous request")),
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) {
+        self.varint(field << 3);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+/// `Message.ResponseStatus` in rendezvous.proto
+fn status(error: ErrorCode) -> u64 {
+    match error {
+        ErrorCode::InvalidNamespace => 100,
+        ErrorCode::InvalidSignedPeerRecord => 101,
+        ErrorCode::InvalidTtl => 102,
+        ErrorCode::InvalidCookie => 103,
+        ErrorCode::NotAuthorized => 200,
+        ErrorCode::InternalError => 300,
+        ErrorCode::Unavailable => 400,
+    }
+}
+
+fn encode_response(response: Response) -> Vec<u8> {
+    let mut message = Writer::default();
+    let mut body = Writer::default();
+    match response {
+        Response::Register(result) => {
+            message.uint(1, REGISTER_RESPONSE);
+            match result {
+                Ok(ttl) => {
+                    body.uint(1, 0);
+                    body.uint(3, ttl);
+                }
+                Err(error) => body.uint(1, status(error)),
+            }
+            message.bytes(3, &body.0);
+        }
+        Response::Discover(result) => {
+            message.uint(1, DISCOVER_RESPONSE);
+            match result {
+                Ok((registrations, cookie)) => {
+                    for registration in registrations {
+                        let mut register = Writer::default();
+                        register.bytes(1, registration.namespace.to_string().as_bytes());
+                        let envelope = registration.record.into_signed_envelope();
+                        register.bytes(2, &envelope.into_protobuf_encoding());
+                        register.uint(3, registration.ttl);
+                        body.bytes(1, &register.0);
+                    }
+                    body.bytes(2, &cookie.into_wire_encoding());
+                    body.uint(3, 0);
+                }
+                Err(error) => body.uint(3, status(error)),
+            }
+            message.bytes(6, &body.0);
+        }
+    }
+    message.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use libp2p::core::transport::{MemoryTransport, Transport, upgrade::Version};
+    use libp2p::multiaddr::Protocol;
+    use libp2p::rendezvous::client;
+    use libp2p::swarm::{Swarm, SwarmEvent};
+    use libp2p::{SwarmBuilder, noise, yamux};
+    use std::time::Duration;
+
+    fn memory_swarm<B: NetworkBehaviour>(
+        behaviour: impl FnOnce(&libp2p::identity::Keypair) -> B,
+    ) -> Swarm<B> {
+        SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|keys| {
+                MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(keys).unwrap())
+                    .multiplex(yamux::Config::default())
+            })
+            .unwrap()
+            .with_behaviour(behaviour)
+            .unwrap()
+            .build()
+    }
+
+    /// Our server and libp2p's client, so every test checks the wire format
+    /// against the upstream implementation
+    struct Net {
+        server: Swarm<Behaviour>,
+        client: Swarm<client::Behaviour>,
+        server_id: PeerId,
+    }
+
+    impl Net {
+        async fn connect(registry: Registry) -> Self {
+            let mut server = memory_swarm(|_| {
+                Behaviour::new(registry, rendezvous::MIN_TTL, rendezvous::MAX_TTL)
+            });
+            let server_id = *server.local_peer_id();
+            let addr: Multiaddr = Protocol::Memory(rand::random::<u64>()).into();
+            server.listen_on(addr.clone()).unwrap();
+
+            let mut client = memory_swarm(|keys| client::Behaviour::new(keys.clone()));
+            client.add_external_address(Protocol::Memory(rand::random::<u64>()).into());
+            client.dial(addr.with(Protocol::P2p(server_id))).unwrap();
+
+            let mut net = Net {
+                server,
+                client,
+                server_id,
+            };
+            net.run(|_, event| {
+                matches!(event, SwarmEvent::ConnectionEstablished { .. }).then_some(())
+            })
+            .await;
+            net
+        }
+
+        /// Drives both swarms until `f` picks an event of the client
+        async fn run<T>(
+            &mut self,
+            mut f: impl FnMut(&mut Swarm<client::Behaviour>, SwarmEvent<client::Event>) -> Option<T>,
+        ) -> T {
+            let run = async {
+                loop {
+                    tokio::select! {
+                        _ = self.server.select_next_some() => {}
+                        event = self.client.select_next_some() => {
+                            if let Some(found) = f(&mut self.client, event) {
+                                return found;
+                            }
+                        }
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), run)
+                .await
+                .expect("No answer within 10s")
+        }
+
+        async fn register(
+            &mut self,
+            namespace: &Namespace,
+            ttl: Option<Ttl>,
+        ) -> Result<Ttl, ErrorCode> {
+            let server_id = self.server_id;
+            self.client
+                .behaviour_mut()
+                .register(namespace.clone(), server_id, ttl)
+                .unwrap();
+            self.run(|_, event| match event {
+                SwarmEvent::Behaviour(client::Event::Registered { ttl, .. }) => Some(Ok(ttl)),
+                SwarmEvent::Behaviour(client::Event::RegisterFailed { error, .. }) => {
+                    Some(Err(error))
+                }
+                _ => None,
+            })
+            .await
+        }
+
+        async fn discover(
+            &mut self,
+            namespace: Option<Namespace>,
+            cookie: Option<Cookie>,
+            limit: Option<u64>,
+        ) -> Result<(Vec<Registration>, Cookie), ErrorCode> {
+            let server_id = self.server_id;
+            self.client
+                .behaviour_mut()
+                .discover(namespace, cookie, limit, server_id);
+            self.run(|_, event| match event {
+                SwarmEvent::Behaviour(client::Event::Discovered {
+                    registrations,
+                    cookie,
+                    ..
+                }) => Some(Ok((registrations, cookie))),
+                SwarmEvent::Behaviour(client::Event::DiscoverFailed { error, .. }) => {
+                    Some(Err(error))
+                }
+                _ => None,
+            })
+            .await
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_registrations_over_the_cap() {
+        let mut net = Net::connect(Registry::new(1, 8)).await;
+        let first = Namespace::from_static("first");
+        let second = Namespace::from_static("second");
+
+        assert_eq!(
+            net.register(&first, None).await,
+            Ok(rendezvous::DEFAULT_TTL)
+        );
+        assert_eq!(
+            net.register(&second, None).await,
+            Err(ErrorCode::Unavailable)
+        );
+
+        let (registrations, _) = net.discover(None, None, None).await.unwrap();
+        assert_eq!(registrations.len(), 1);
+        assert_eq!(registrations[0].namespace, first);
+        assert_eq!(
+            registrations[0].record.peer_id(),
+            *net.client.local_peer_id()
+        );
+        assert_eq!(net.server.behaviour().registry().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_ttl_out_of_bounds() {
+        let mut net = Net::connect(Registry::new(8, 8)).await;
+        let ns = Namespace::from_static("room");
+        let ttl = rendezvous::MAX_TTL + 1;
+        assert_eq!(
+            net.register(&ns, Some(ttl)).await,
+            Err(ErrorCode::InvalidTtl)
+        );
+        assert_eq!(
+            net.register(&ns, Some(rendezvous::MIN_TTL)).await,
+            Ok(rendezvous::MIN_TTL)
+        );
+    }
+
+    #[tokio::test]
+    async fn unregister_removes_the_registration() {
+        let mut net = Net::connect(Registry::new(8, 8)).await;
+        let ns = Namespace::from_static("room");
+        net.register(&ns, None).await.unwrap();
+
+        let server_id = net.server_id;
+        net.client.behaviour_mut().unregister(ns.clone(), server_id);
+        // We don't answer unregisters, wait for the server to see it
+        let unregistered = async {
+            loop {
+                tokio::select! {
+                    event = net.server.select_next_some() => {
+                        if let SwarmEvent::Behaviour(Event::PeerUnregistered { namespace, .. }) = event {
+                            return namespace;
+                        }
+                    }
+                    _ = net.client.select_next_some() => {}
+                }
+            }
+        };
+        let namespace = tokio::time::timeout(Duration::from_secs(10), unregistered)
+            .await
+            .expect("No unregister within 10s");
+        assert_eq!(namespace, ns);
+
+        let (registrations, _) = net.discover(Some(ns), None, None).await.unwrap();
+        assert!(registrations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cookies_page_through_registrations() {
+        let mut net = Net::connect(Registry::new(8, 8)).await;
+        let first = Namespace::from_static("first");
+        let second = Namespace::from_static("second");
+        net.register(&first, None).await.unwrap();
+        net.register(&second, None).await.unwrap();
+
+        let (page, cookie) = net.discover(None, None, Some(1)).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].namespace, first);
+        let (page, cookie) = net.discover(None, Some(cookie), Some(1)).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].namespace, second);
+        let (page, _) = net.discover(None, Some(cookie), None).await.unwrap();
+        assert!(page.is_empty());
+
+        // A cookie is tied to the namespace it was handed out for
+        let (_, cookie) = net.discover(Some(first), None, None).await.unwrap();
+        assert_eq!(
+            net.discover(Some(second), Some(cookie), None)
+                .await
+                .unwrap_err(),
+            ErrorCode::InvalidCookie
+        );
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_are_rejected() {
+        let mut frame = Writer::default();
+        frame.varint(MAX_MESSAGE_LEN as u64 + 1);
+        let e = read_frame(&mut futures::io::Cursor::new(frame.0))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Ten continuation bytes never end the length prefix
+        let e = read_frame(&mut futures::io::Cursor::new(vec![0xff; 10]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let e = decode_request(&[0x08, 0x07]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -10,19 +10,218 @@
 //! Now for sending a message what we can do is open a stream which a client program with
 //! interact with. So once a stream is opened with a stream request you just send a topic,
 //! and the message over the stream. Receaving messages would be done the same way.
+//!
+//! Messages on the stream are [`MessageFrame`]s, see [`write_frame`] and [`read_frame`].
+//!
+//! Only clients listed in the authorized clients file can connect, see [`authorized_clients`].
+//! Local tools that don't want an identity can use the control socket instead, see
+//! [`crate::unixsocket`]. It speaks [`ControlFrame`]s either as CBOR frames or as
+//! lines of JSON, see [`write_json_frame`] and [`json_frames`].
 
-// use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-
-use futures::{AsyncWriteExt, StreamExt};
-use libp2p::request_response::{self as reqres, ProtocolSupport, cbor};
-use libp2p::swarm::{NetworkBehaviour, Stream, Swarm, SwarmEvent, dial_opts::DialOpts};
-use libp2p::{Multiaddr, PeerId, StreamProtocol, SwarmBuilder, gossipsub};
+use futures::future::BoxFuture;
+use futures::io::WriteHalf;
+use futures::stream::{BoxStream, FuturesUnordered, SelectAll};
+use futures::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, StreamExt,
+};
+use libp2p::request_response::{self as reqres, ProtocolSupport, ResponseChannel, cbor};
+use libp2p::swarm::{NetworkBehaviour, Stream, Swarm, SwarmEvent, behaviour::toggle::Toggle};
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, SwarmBuilder, TransportError, allow_block_list, gossipsub,
+    multiaddr::Protocol,
+};
 use libp2p_stream as stream;
-use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tracing::info;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::{fmt, fs, io};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, error::TrySendError};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::unixsocket::UnixEvent;
 
 pub const MAGIC_PROTOCOL: StreamProtocol = StreamProtocol::new("/magic");
+/// Largest frame we accept, anything bigger is most likely garbage
+pub const MAX_FRAME: usize = 1 << 20;
+/// Frames queued for a client before it is considered too slow and dropped
+pub(crate) const CLIENT_QUEUE: usize = 64;
+
+/// A pubsub message on the STRM stream. The daemon fills in everything,
+/// frames sent by the client only need `topic` and `data`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageFrame {
+    pub topic: String,
+    pub source: Option<PeerId>,
+    pub message_id: Option<String>,
+    pub sequence_number: Option<u64>,
+    #[serde(with = "payload")]
+    pub data: Vec<u8>,
+}
+
+/// How [`MessageFrame::data`] looks in JSON: a plain string if it is valid
+/// UTF-8 and `{"base64": "..."}` otherwise. CBOR keeps the byte array.
+mod payload {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Json {
+        Text(String),
+        Binary { base64: String },
+    }
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return data.serialize(serializer);
+        }
+        match std::str::from_utf8(data) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => Json::Binary {
+                base64: STANDARD.encode(data),
+            }
+            .serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Vec::deserialize(deserializer);
+        }
+        match Json::deserialize(deserializer)? {
+            Json::Text(text) => Ok(text.into_bytes()),
+            Json::Binary { base64 } => STANDARD.decode(base64).map_err(D::Error::custom),
+        }
+    }
+}
+
+impl MessageFrame {
+    /// A message for the daemon to publish
+    pub fn new(topic: String, data: Vec<u8>) -> Self {
+        MessageFrame {
+            topic,
+            source: None,
+            message_id: None,
+            sequence_number: None,
+            data,
+        }
+    }
+
+    pub fn from_gossipsub(message_id: &gossipsub::MessageId, message: gossipsub::Message) -> Self {
+        MessageFrame {
+            topic: message.topic.into_string(),
+            source: message.source,
+            message_id: Some(message_id.to_string()),
+            sequence_number: message.sequence_number,
+            data: message.data,
+        }
+    }
+}
+
+/// Everything sent over the control socket. Clients send `Request` and
+/// `Message`, the daemon answers with `Response` and `Message`. `id` is
+/// picked by the client and given back with the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlFrame {
+    Request { id: u64, request: RequestEvent },
+    Response { id: u64, response: ResponseEvent },
+    Message(MessageFrame),
+}
+
+/// Writes `frame` as CBOR behind its length as a big endian u32
+pub async fn write_frame<W: AsyncWrite + Unpin, T: Serialize>(
+    io: &mut W,
+    frame: &T,
+) -> io::Result<()> {
+    let bytes = cbor4ii::serde::to_vec(Vec::new(), frame)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if bytes.len() > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame too large",
+        ));
+    }
+
+    io.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    io.write_all(&bytes).await?;
+    io.flush().await
+}
+
+/// Reads a frame written by [`write_frame`]
+pub async fn read_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(io: &mut R) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too large",
+        ));
+    }
+
+    let mut bytes = vec![0u8; len];
+    io.read_exact(&mut bytes).await?;
+    cbor4ii::serde::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Every frame read from `io` until it closes or sends something invalid
+pub fn frames<R, T>(io: R) -> BoxStream<'static, T>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    T: DeserializeOwned + Send + 'static,
+{
+    futures::stream::unfold(io, |mut io| async move {
+        match read_frame(&mut io).await {
+            Ok(frame) => Some((frame, io)),
+            Err(e) => {
+                debug!("Message stream closed: {}", e);
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Writes `frame` as a single line of JSON, for scripts that can't do CBOR
+pub async fn write_json_frame<W: AsyncWrite + Unpin, T: Serialize>(
+    io: &mut W,
+    frame: &T,
+) -> io::Result<()> {
+    let mut bytes = serde_json::to_vec(frame)?;
+    bytes.push(b'\n');
+    io.write_all(&bytes).await?;
+    io.flush().await
+}
+
+/// Every line of JSON read from `io` until it closes. Lines that don't
+/// parse are skipped so a typo doesn't end the session.
+pub fn json_frames<R, T>(io: R) -> BoxStream<'static, T>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    T: DeserializeOwned + Send + 'static,
+{
+    futures::io::BufReader::new(io)
+        .lines()
+        .filter_map(|line| async move {
+            let line = match line {
+                Ok(line) if line.trim().is_empty() => return None,
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Skipping unreadable line: {}", e);
+                    return None;
+                }
+            };
+            match serde_json::from_str(&line) {
+                Ok(frame) => Some(frame),
+                Err(e) => {
+                    warn!("Skipping invalid frame {:?}: {}", line, e);
+                    None
+                }
+            }
+        })
+        .boxed()
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, derive_more::FromStr)]
 pub enum RequestType {
@@ -30,6 +229,10 @@ pub enum RequestType {
     PART,
     MESG,
     STRM,
+    /// Channels we are in, `channel` is ignored
+    LIST,
+    /// Peers in `channel`, or all connected peers if it is empty
+    PEER,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,13 +247,87 @@ pub struct RequestEvent {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResponseEvent {
     Ok,
-    Err,
+    /// A MESG went out under this id
+    Published {
+        message_id: String,
+    },
+    Channels(Vec<String>),
+    Peers {
+        count: usize,
+    },
+    Err(ResponseError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResponseError {
+    NotSubscribed(String),
+    /// Nobody else is in the channel yet
+    InsufficientPeers,
+    /// Any other `gossipsub::PublishError`
+    PublishFailed(String),
+    /// The channel couldn't be joined, see `gossipsub::SubscriptionError`
+    SubscribeFailed(String),
+    InvalidChannel(String),
+    EmptyMessage,
+    /// The request never made it to the network, ie. it is shutting down
+    Unavailable(String),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::NotSubscribed(channel) => write!(f, "Not in {}", channel),
+            ResponseError::InsufficientPeers => write!(f, "No peers in the channel"),
+            ResponseError::PublishFailed(e) => write!(f, "Could not publish: {}", e),
+            ResponseError::SubscribeFailed(e) => write!(f, "Could not subscribe: {}", e),
+            ResponseError::InvalidChannel(channel) => write!(f, "Invalid channel {:?}", channel),
+            ResponseError::EmptyMessage => write!(f, "Empty message"),
+            ResponseError::Unavailable(e) => write!(f, "Unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+impl From<gossipsub::PublishError> for ResponseError {
+    fn from(e: gossipsub::PublishError) -> Self {
+        match e {
+            gossipsub::PublishError::NoPeersSubscribedToTopic => ResponseError::InsufficientPeers,
+            e => ResponseError::PublishFailed(e.to_string()),
+        }
+    }
+}
+
+impl From<gossipsub::SubscriptionError> for ResponseError {
+    fn from(e: gossipsub::SubscriptionError) -> Self {
+        match e {
+            gossipsub::SubscriptionError::PublishError(e) => {
+                ResponseError::SubscribeFailed(e.to_string())
+            }
+            gossipsub::SubscriptionError::NotAllowed => {
+                ResponseError::SubscribeFailed("Subscription not allowed".to_string())
+            }
+        }
+    }
+}
+
+impl From<ResponseError> for ResponseEvent {
+    fn from(e: ResponseError) -> Self {
+        ResponseEvent::Err(e)
+    }
+}
+
+/// Channels are gossipsub topics, keep them to something a user can type
+fn valid_channel(channel: &str) -> bool {
+    !channel.is_empty() && !channel.contains(char::is_whitespace)
 }
 
 #[derive(NetworkBehaviour)]
 pub struct SocketBehaviour {
     socket: cbor::Behaviour<RequestEvent, ResponseEvent>,
     stream: stream::Behaviour,
+    /// Only the server checks who is connecting
+    allowed: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
 }
 
 impl SocketBehaviour {
@@ -60,16 +337,32 @@ impl SocketBehaviour {
             reqres::Config::default(),
         );
         let stream = stream::Behaviour::new();
-        Self { socket, stream }
+        let allowed = Toggle::from(None);
+        Self {
+            socket,
+            stream,
+            allowed,
+        }
     }
-    pub(crate) fn new_server() -> Self {
+    /// Connections from anyone not in `authorized` are refused
+    pub(crate) fn new_server(authorized: &HashSet<PeerId>) -> Self {
         let socket = cbor::Behaviour::new(
             [(MAGIC_PROTOCOL, ProtocolSupport::Inbound)],
             reqres::Config::default(),
         );
 
         let stream = stream::Behaviour::new();
-        Self { socket, stream }
+
+        let mut allowed = allow_block_list::Behaviour::<allow_block_list::AllowedPeers>::default();
+        for peer_id in authorized {
+            allowed.allow_peer(*peer_id);
+        }
+        let allowed = Toggle::from(Some(allowed));
+        Self {
+            socket,
+            stream,
+            allowed,
+        }
     }
     pub fn new_control(&self) -> stream::Control {
         self.stream.new_control()
@@ -83,38 +376,150 @@ impl SocketBehaviour {
         self.socket.send_request(peer, request)
     }
 
-    pub(crate) fn send_response(&mut self) -> Result<(), ResponseEvent> {
-        unimplemented!()
+    /// Gives the response back if the client is gone
+    pub(crate) fn send_response(
+        &mut self,
+        channel: ResponseChannel<ResponseEvent>,
+        response: ResponseEvent,
+    ) -> Result<(), ResponseEvent> {
+        self.socket.send_response(channel, response)
+    }
+}
+
+/// Reads the peers allowed to use the socket from `path`, one PeerId per
+/// line like `authorized_keys`. Blank lines and `#` comments are skipped.
+pub fn authorized_clients(path: &Path) -> io::Result<HashSet<PeerId>> {
+    let mut authorized = HashSet::new();
+    for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let peer_id = line.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), n + 1, e),
+            )
+        })?;
+        authorized.insert(peer_id);
     }
+    Ok(authorized)
 }
 
+/// If `addr` can only be reached from this machine
+pub fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+/// What the user asked for, `reply` has to be answered with the outcome
 #[derive(Debug)]
 pub enum ForwardRequest {
-    Message { text: String, channel: String },
-    Subscribe { channel: String },
-    Unsubscribe { channel: String },
+    Message {
+        data: Vec<u8>,
+        channel: String,
+        reply: oneshot::Sender<ResponseEvent>,
+    },
+    Subscribe {
+        channel: String,
+        reply: oneshot::Sender<ResponseEvent>,
+    },
+    Unsubscribe {
+        channel: String,
+        reply: oneshot::Sender<ResponseEvent>,
+    },
+    Channels {
+        reply: oneshot::Sender<ResponseEvent>,
+    },
+    /// `channel` is `None` for every connected peer
+    Peers {
+        channel: Option<String>,
+        reply: oneshot::Sender<ResponseEvent>,
+    },
+}
+
+/// A local client, either a libp2p peer or a connection on the control socket or stdio
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum ClientId {
+    Peer(PeerId),
+    Local(u64),
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientId::Peer(peer_id) => write!(f, "<{}>", peer_id),
+            ClientId::Local(n) => write!(f, "local:{}", n),
+        }
+    }
 }
 
+/// Where the answer to a request goes
+enum Responder {
+    Peer(ResponseChannel<ResponseEvent>),
+    Local {
+        tx: mpsc::Sender<ControlFrame>,
+        id: u64,
+    },
+}
+
+/// An answer from the network thread
+struct Reply {
+    responder: Responder,
+    response: ResponseEvent,
+    /// Set for JOIN so the client is only added once the subscribe worked
+    joined: Option<(ClientId, String)>,
+}
+
+/// Requests waiting on a reply from the network thread
+type Pending = FuturesUnordered<BoxFuture<'static, Reply>>;
+
 /// Actions to preform based on events ganerated
 enum SwarmOpts {
-    OpenStream,
-    Forward(ForwardRequest),
+    Request(PeerId, RequestEvent, ResponseChannel<ResponseEvent>),
     ClientFound(PeerId),
     ClientLost(PeerId),
 }
 
-fn init_swarm(interface: Multiaddr) -> Swarm<SocketBehaviour> {
+/// What a request turns into
+enum Action {
+    Forward(ForwardRequest, oneshot::Receiver<ResponseEvent>),
+    OpenStream,
+    Respond(ResponseEvent),
+}
+
+/// The swarm local clients connect to, listening on `interface`. It should
+/// only listen on local interfaces since this give absolute control over the
+/// progeam. Only peers in `authorized` can connect.
+///
+/// Has to be called from inside the runtime that runs [`user_socket_handler`].
+pub fn init_swarm(
+    interface: Multiaddr,
+    authorized: &HashSet<PeerId>,
+) -> Result<Swarm<SocketBehaviour>, TransportError<io::Error>> {
+    if !is_loopback(&interface) {
+        warn!(
+            "Socket is listening on {}, anyone on the network can reach it",
+            interface
+        );
+    }
+    if authorized.is_empty() {
+        warn!("No authorized clients, every connection will be refused");
+    }
+
     let mut swarm = SwarmBuilder::with_new_identity()
         .with_tokio()
         .with_quic()
-        .with_behaviour(|_| SocketBehaviour::new_server())
+        .with_behaviour(|_| SocketBehaviour::new_server(authorized))
         .expect("Won't fail")
         .build();
 
-    let dial = DialOpts::unknown_peer_id().address(interface).build();
-    swarm.dial(dial).unwrap();
+    swarm.listen_on(interface)?;
 
-    swarm
+    Ok(swarm)
 }
 
 fn swarm_event_handle(event: SwarmEvent<SocketBehaviourEvent>) -> Option<SwarmOpts> {
@@ -123,105 +528,579 @@ fn swarm_event_handle(event: SwarmEvent<SocketBehaviourEvent>) -> Option<SwarmOp
             info!("Listening on {}", address);
             None
         }
+        SwarmEvent::IncomingConnectionError {
+            send_back_addr,
+            error,
+            ..
+        } => {
+            warn!("Refused client at {}: {}", send_back_addr, error);
+            None
+        }
         SwarmEvent::ConnectionEstablished { peer_id, .. } => Some(SwarmOpts::ClientFound(peer_id)),
-        SwarmEvent::ConnectionClosed { peer_id, cause, .. } => Some(SwarmOpts::ClientLost(peer_id)),
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } => Some(SwarmOpts::ClientLost(peer_id)),
         // We don't need to handle the stream event
         SwarmEvent::Behaviour(SocketBehaviourEvent::Socket(e)) => match e {
-            reqres::Event::Message { message, .. } => match message {
-                // THIS IS WHERE WE GET `Event::InboundFailure` on the client!
-                reqres::Message::Request {
-                    request, channel, ..
-                } => match request.kind {
-                    RequestType::JOIN => Some(SwarmOpts::Forward(ForwardRequest::Subscribe {
-                        channel: request.channel,
-                    })),
-                    RequestType::PART => Some(SwarmOpts::Forward(ForwardRequest::Unsubscribe {
-                        channel: request.channel,
-                    })),
-                    RequestType::STRM => Some(SwarmOpts::OpenStream),
-                    RequestType::MESG => match request.data {
-                        Some(text) => Some(SwarmOpts::Forward(ForwardRequest::Message {
-                            text,
-                            channel: request.channel,
-                        })),
-                        _ => None, // Empty message so don't forward
+            reqres::Event::Message {
+                peer,
+                message:
+                    reqres::Message::Request {
+                        request, channel, ..
                     },
-                },
-                _ => unreachable!(), // We shouldn't ever get a response
-            },
-            reqres::Event::ResponseSent { request_id, .. } => None,
-            reqres::Event::InboundFailure {
-                request_id, error, ..
-            } => None,
+                ..
+            } => Some(SwarmOpts::Request(peer, request, channel)),
+            reqres::Event::Message { .. } => unreachable!(), // We shouldn't ever get a response
+            reqres::Event::ResponseSent { .. } => None,
+            reqres::Event::InboundFailure { peer, error, .. } => {
+                warn!("Could not answer <{}>: {}", peer, error);
+                None
+            }
             _ => unreachable!(), // We don't send requests
         },
         _ => None,
     }
 }
 
-async fn inbound_handle(
-    event: SwarmEvent<SocketBehaviourEvent>,
-    message_control: &mut libp2p_stream::Control,
-    message_stream: &mut Option<Stream>,
-    user_input_tx: &mut UnboundedSender<ForwardRequest>,
-    client_id: &mut Option<PeerId>,
-) {
-    let opt = match swarm_event_handle(event) {
-        Some(x) => x,
-        _ => return,
+/// Checks `request` and turns it into what the network thread understands
+fn request_action(request: RequestEvent) -> Action {
+    let checks_channel = matches!(
+        request.kind,
+        RequestType::JOIN | RequestType::PART | RequestType::MESG
+    );
+    if checks_channel && !valid_channel(&request.channel) {
+        return Action::Respond(ResponseError::InvalidChannel(request.channel).into());
+    }
+
+    let (reply, rx) = oneshot::channel();
+    let forward = match request.kind {
+        RequestType::JOIN => ForwardRequest::Subscribe {
+            channel: request.channel,
+            reply,
+        },
+        RequestType::PART => ForwardRequest::Unsubscribe {
+            channel: request.channel,
+            reply,
+        },
+        RequestType::STRM => return Action::OpenStream,
+        RequestType::MESG => match request.data {
+            Some(text) => ForwardRequest::Message {
+                data: text.into_bytes(),
+                channel: request.channel,
+                reply,
+            },
+            None => return Action::Respond(ResponseError::EmptyMessage.into()),
+        },
+        RequestType::LIST => ForwardRequest::Channels { reply },
+        RequestType::PEER => ForwardRequest::Peers {
+            channel: Some(request.channel).filter(|c| !c.is_empty()),
+            reply,
+        },
     };
+    Action::Forward(forward, rx)
+}
+
+/// Where pubsub messages are written to
+enum Writer {
+    Stream(WriteHalf<Stream>),
+    Local(mpsc::Sender<ControlFrame>),
+}
+
+impl Writer {
+    async fn send(&mut self, frame: MessageFrame) -> io::Result<()> {
+        match self {
+            Writer::Stream(writer) => write_frame(writer, &frame).await,
+            Writer::Local(tx) => tx
+                .send(ControlFrame::Message(frame))
+                .await
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    /// Moves the writer to its own task so a slow client only holds up
+    /// itself. The returned queue closes once a write fails.
+    fn spawn(mut self, client_id: ClientId) -> mpsc::Sender<MessageFrame> {
+        let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+        tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                if let Err(e) = self.send(frame).await {
+                    warn!("Could not write to {}: {}", client_id, e);
+                    break;
+                }
+            }
+        });
+        tx
+    }
+}
+
+/// A local client and the channels it joined
+#[derive(Default)]
+struct Client {
+    /// Queue of the task writing pubsub messages, see [`Writer::spawn`]
+    writer: Option<mpsc::Sender<MessageFrame>>,
+    channels: HashSet<String>,
+    /// Only set for control socket and stdio clients, where everything is sent
+    control: Option<mpsc::Sender<ControlFrame>>,
+}
+
+/// Every client attached to the socket. The node is subscribed to each
+/// channel at least one of them joined.
+#[derive(Default)]
+struct Clients {
+    clients: HashMap<ClientId, Client>,
+    /// Messages the libp2p clients want published
+    frames: SelectAll<BoxStream<'static, (ClientId, MessageFrame)>>,
+}
+
+impl Clients {
+    fn open(&mut self, client_id: ClientId, stream: Stream) {
+        let (reader, writer) = stream.split();
+        self.clients.entry(client_id).or_default().writer =
+            Some(Writer::Stream(writer).spawn(client_id));
+        self.frames
+            .push(frames(reader).map(move |frame| (client_id, frame)).boxed());
+    }
+
+    /// If `client_id` itself is in `channel`
+    fn joined(&self, client_id: &ClientId, channel: &str) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|client| client.channels.contains(channel))
+    }
+
+    /// If any client other than `client_id` is in `channel`
+    fn shared(&self, client_id: &ClientId, channel: &str) -> bool {
+        self.clients
+            .iter()
+            .any(|(id, client)| id != client_id && client.channels.contains(channel))
+    }
 
-    match opt {
-        SwarmOpts::ClientFound(peer_id) => *client_id = Some(peer_id),
-        SwarmOpts::ClientLost(_) => *client_id = None,
-        SwarmOpts::Forward(req) => user_input_tx.send(req).unwrap(),
-        SwarmOpts::OpenStream => {
-            if let Some(peer_id) = client_id {
-                *message_stream = Some(
-                    message_control
-                        .open_stream(*peer_id, MAGIC_PROTOCOL)
-                        .await
-                        .unwrap(),
+    /// Drops the client, returning the channels nobody is left in
+    fn remove(&mut self, client_id: &ClientId) -> Vec<String> {
+        let Some(client) = self.clients.remove(client_id) else {
+            return Vec::new();
+        };
+        client
+            .channels
+            .into_iter()
+            .filter(|channel| !self.shared(client_id, channel))
+            .collect()
+    }
+
+    /// Answers what can be answered from the client's own subscriptions,
+    /// everything else is handed back to be sent to the network thread.
+    fn request(&mut self, client_id: ClientId, request: ForwardRequest) -> Option<ForwardRequest> {
+        let (response, reply) = match request {
+            ForwardRequest::Subscribe { channel, reply } => {
+                if !self.shared(&client_id, &channel) {
+                    return Some(ForwardRequest::Subscribe { channel, reply });
+                }
+                let client = self.clients.entry(client_id).or_default();
+                client.channels.insert(channel);
+                (ResponseEvent::Ok, reply)
+            }
+            ForwardRequest::Unsubscribe { channel, reply } => {
+                let client = self.clients.entry(client_id).or_default();
+                if !client.channels.remove(&channel) {
+                    (ResponseError::NotSubscribed(channel).into(), reply)
+                } else if self.shared(&client_id, &channel) {
+                    (ResponseEvent::Ok, reply)
+                } else {
+                    return Some(ForwardRequest::Unsubscribe { channel, reply });
+                }
+            }
+            // Clients only get to use the channels they joined
+            ForwardRequest::Message { channel, reply, .. }
+                if !self.joined(&client_id, &channel) =>
+            {
+                (ResponseError::NotSubscribed(channel).into(), reply)
+            }
+            ForwardRequest::Peers {
+                channel: Some(channel),
+                reply,
+            } if !self.joined(&client_id, &channel) => {
+                (ResponseError::NotSubscribed(channel).into(), reply)
+            }
+            ForwardRequest::Channels { reply } => {
+                let client = self.clients.entry(client_id).or_default();
+                let mut channels: Vec<String> = client.channels.iter().cloned().collect();
+                channels.sort();
+                (ResponseEvent::Channels(channels), reply)
+            }
+            request => return Some(request),
+        };
+        let _ = reply.send(response);
+        None
+    }
+}
+
+/// State of the socket thread
+struct Server {
+    swarm: Swarm<SocketBehaviour>,
+    /// Stream to send pubsub messages to users
+    message_control: stream::Control,
+    user_input_tx: UnboundedSender<ForwardRequest>,
+    clients: Clients,
+    pending: Pending,
+}
+
+impl Server {
+    fn respond(&mut self, responder: Responder, response: ResponseEvent) {
+        match responder {
+            Responder::Peer(channel) => {
+                if let Err(response) = self.swarm.behaviour_mut().send_response(channel, response) {
+                    warn!("Client left before getting {:?}", response);
+                }
+            }
+            Responder::Local { tx, id } => {
+                if let Err(e) = tx.try_send(ControlFrame::Response { id, response }) {
+                    warn!("Could not give control client response {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    /// Tells the network thread to leave `channel`, nobody is waiting on the outcome
+    fn leave(&mut self, channel: String) {
+        let (reply, _) = oneshot::channel();
+        let _ = self
+            .user_input_tx
+            .send(ForwardRequest::Unsubscribe { channel, reply });
+    }
+
+    fn attach(&mut self, client_id: ClientId, control: Option<mpsc::Sender<ControlFrame>>) {
+        info!("Client {} attached", client_id);
+        self.clients.clients.entry(client_id).or_default().control = control;
+    }
+
+    fn detach(&mut self, client_id: ClientId) {
+        // Already gone if we dropped it
+        if !self.clients.clients.contains_key(&client_id) {
+            return;
+        }
+        info!("Client {} left", client_id);
+        for channel in self.clients.remove(&client_id) {
+            self.leave(channel);
+        }
+    }
+
+    async fn request(&mut self, client_id: ClientId, responder: Responder, request: RequestEvent) {
+        match request_action(request) {
+            Action::Respond(response) => self.respond(responder, response),
+            Action::Forward(req, rx) => {
+                let joined = match &req {
+                    ForwardRequest::Subscribe { channel, .. } => Some((client_id, channel.clone())),
+                    _ => None,
+                };
+                if let Some(req) = self.clients.request(client_id, req)
+                    && self.user_input_tx.send(req).is_err()
+                {
+                    let error = ResponseError::Unavailable("Network is not running".to_string());
+                    self.respond(responder, error.into());
+                    return;
+                }
+                self.pending.push(
+                    async move {
+                        let response = rx.await.unwrap_or_else(|_| {
+                            ResponseError::Unavailable("Request was dropped".to_string()).into()
+                        });
+                        Reply {
+                            responder,
+                            response,
+                            joined,
+                        }
+                    }
+                    .boxed(),
                 );
             }
+            Action::OpenStream => {
+                let response = self.open_stream(client_id).await;
+                self.respond(responder, response);
+            }
         }
     }
-}
 
-async fn outbound_handle(event: gossipsub::Event, message_stream: &mut Option<Stream>) {
-    match event {
-        gossipsub::Event::Message { message, .. } => {
-            if let Some(stream) = message_stream {
-                stream.write_all(&message.data).await.unwrap();
+    /// Starts sending pubsub messages to the client
+    async fn open_stream(&mut self, client_id: ClientId) -> ResponseEvent {
+        match client_id {
+            ClientId::Peer(peer_id) => {
+                match self
+                    .message_control
+                    .open_stream(peer_id, MAGIC_PROTOCOL)
+                    .await
+                {
+                    Ok(stream) => {
+                        self.clients.open(client_id, stream);
+                        ResponseEvent::Ok
+                    }
+                    Err(e) => ResponseError::Unavailable(e.to_string()).into(),
+                }
+            }
+            // Messages go over the same connection
+            ClientId::Local(_) => {
+                let client = self.clients.clients.entry(client_id).or_default();
+                client.writer = client
+                    .control
+                    .clone()
+                    .map(|tx| Writer::Local(tx).spawn(client_id));
+                ResponseEvent::Ok
+            }
+        }
+    }
+
+    async fn inbound_handle(&mut self, event: SwarmEvent<SocketBehaviourEvent>) {
+        let opt = match swarm_event_handle(event) {
+            Some(x) => x,
+            _ => return,
+        };
+
+        match opt {
+            SwarmOpts::ClientFound(peer_id) => self.attach(ClientId::Peer(peer_id), None),
+            SwarmOpts::ClientLost(peer_id) => self.detach(ClientId::Peer(peer_id)),
+            SwarmOpts::Request(peer_id, request, channel) => {
+                let client_id = ClientId::Peer(peer_id);
+                self.request(client_id, Responder::Peer(channel), request)
+                    .await
             }
         }
-        _ => {}
+    }
+
+    async fn unix_handle(&mut self, event: UnixEvent) {
+        match event {
+            UnixEvent::Attached(n, tx) => self.attach(ClientId::Local(n), Some(tx)),
+            UnixEvent::Detached(n) => self.detach(ClientId::Local(n)),
+            UnixEvent::Frame(n, frame) => {
+                let client_id = ClientId::Local(n);
+                match frame {
+                    ControlFrame::Request { id, request } => {
+                        let Some(tx) = self
+                            .clients
+                            .clients
+                            .get(&client_id)
+                            .and_then(|c| c.control.clone())
+                        else {
+                            return;
+                        };
+                        self.request(client_id, Responder::Local { tx, id }, request)
+                            .await
+                    }
+                    ControlFrame::Message(frame) => self.publish_frame(client_id, frame),
+                    ControlFrame::Response { .. } => {
+                        warn!("Client {} sent a response, ignoring", client_id)
+                    }
+                }
+            }
+        }
+    }
+
+    fn reply_handle(&mut self, reply: Reply) {
+        if let (Some((client_id, channel)), ResponseEvent::Ok) = (reply.joined, &reply.response) {
+            // They might have left while we were subscribing
+            if let Some(client) = self.clients.clients.get_mut(&client_id) {
+                client.channels.insert(channel);
+            }
+        }
+        self.respond(reply.responder, reply.response);
+    }
+
+    /// Sends a pubsub message to every client in its channel. Clients that
+    /// can't keep up are dropped instead of holding up everyone else.
+    fn outbound_handle(&mut self, event: gossipsub::Event) {
+        let gossipsub::Event::Message {
+            message_id,
+            message,
+            ..
+        } = event
+        else {
+            return;
+        };
+
+        let frame = MessageFrame::from_gossipsub(&message_id, message);
+        let mut dropped = Vec::new();
+        for (client_id, client) in self.clients.clients.iter() {
+            if !client.channels.contains(&frame.topic) {
+                continue;
+            }
+            let Some(writer) = &client.writer else {
+                continue;
+            };
+            match writer.try_send(frame.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("{} can't keep up with its messages, dropping it", client_id);
+                    dropped.push(*client_id);
+                }
+                // The writer task already logged why
+                Err(TrySendError::Closed(_)) => dropped.push(*client_id),
+            }
+        }
+        for client_id in dropped {
+            self.drop_client(client_id);
+        }
+    }
+
+    /// Detaches a client we gave up on. A peer is disconnected, a local
+    /// client's connection ends once nothing here holds its sender.
+    fn drop_client(&mut self, client_id: ClientId) {
+        self.detach(client_id);
+        if let ClientId::Peer(peer_id) = client_id {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+    }
+
+    /// A message a client sent over its stream. Failures are only logged
+    /// since there is no request to answer.
+    fn publish_frame(&mut self, client_id: ClientId, frame: MessageFrame) {
+        if !valid_channel(&frame.topic) {
+            warn!(
+                "{} sent a message to invalid channel {:?}",
+                client_id, frame.topic
+            );
+            return;
+        }
+        if !self.clients.joined(&client_id, &frame.topic) {
+            warn!(
+                "{} sent a message to {} without joining it, dropping it",
+                client_id, frame.topic
+            );
+            return;
+        }
+
+        let (reply, _) = oneshot::channel();
+        let request = ForwardRequest::Message {
+            data: frame.data,
+            channel: frame.topic,
+            reply,
+        };
+        if self.user_input_tx.send(request).is_err() {
+            warn!("Network is not running, dropping message");
+        }
     }
 }
 
-/// Handler for all local interactions. It will start its own swarm and
-/// should only listen on local interfaces since this give absolute
-/// control over the progeam.
+/// Handler for all local interactions on `swarm`, see [`init_swarm`].
+/// `unix_rx` is set if the same requests are also served on a Unix
+/// domain socket or stdin and stdout, see [`LocalControl::start`].
+///
+/// [`LocalControl::start`]: crate::unixsocket::LocalControl::start
 ///
-/// `user_input_tx` is for messages that are generated by the user.
+/// `user_input_tx` is for messages that are generated by the user, each one
+/// carries a reply that is sent back to the client as its response.
 /// `message_rx` is for recving all pubsub event generated by magic
-/// which will then be sent to the user as is.
+/// which will then be sent as [`MessageFrame`]s to the clients in that channel.
 pub async fn user_socket_handler(
-    interface: Multiaddr,
-    mut user_input_tx: UnboundedSender<ForwardRequest>,
+    swarm: Swarm<SocketBehaviour>,
+    unix_rx: Option<UnboundedReceiver<UnixEvent>>,
+    user_input_tx: UnboundedSender<ForwardRequest>,
     mut message_rx: UnboundedReceiver<gossipsub::Event>,
 ) -> ! {
-    let mut swarm = init_swarm(interface);
-    let mut client_id: Option<PeerId> = None;
-    // Stream to send pubsub messages to user
-    let mut message_control = swarm.behaviour().new_control();
+    let message_control = swarm.behaviour().new_control();
 
-    let mut message_stream: Option<Stream> = None;
+    let (mut unix_rx, has_unix) = match unix_rx {
+        Some(rx) => (rx, true),
+        None => (mpsc::unbounded_channel().1, false),
+    };
+
+    let mut server = Server {
+        swarm,
+        message_control,
+        user_input_tx,
+        clients: Clients::default(),
+        pending: Pending::new(),
+    };
 
     loop {
         tokio::select! {
-            Some(event) = message_rx.recv() => outbound_handle(event, &mut message_stream).await,
-            event = swarm.select_next_some() => inbound_handle(event, &mut message_control, &mut message_stream, &mut user_input_tx, &mut client_id).await,
+            Some(event) = message_rx.recv() => server.outbound_handle(event),
+            Some(reply) = server.pending.next() => server.reply_handle(reply),
+            Some((client_id, frame)) = server.clients.frames.next() => server.publish_frame(client_id, frame),
+            Some(event) = unix_rx.recv(), if has_unix => server.unix_handle(event).await,
+            event = server.swarm.select_next_some() => server.inbound_handle(event).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        // Not valid UTF-8, has to come back untouched
+        let data = vec![0xff, 0x00, 0xfe, b'h', b'i'];
+        let mut buf = Cursor::new(Vec::new());
+        write_frame(
+            &mut buf,
+            &MessageFrame::new("room".to_string(), data.clone()),
+        )
+        .await
+        .unwrap();
+
+        buf.set_position(0);
+        let frame: MessageFrame = read_frame(&mut buf).await.unwrap();
+        assert_eq!(frame.topic, "room");
+        assert_eq!(frame.data, data);
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_are_rejected() {
+        let frame = MessageFrame::new("room".to_string(), vec![0; MAX_FRAME + 1]);
+        let mut buf = Cursor::new(Vec::new());
+        let e = write_frame(&mut buf, &frame).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.get_ref().is_empty());
+
+        let mut buf = Cursor::new(((MAX_FRAME + 1) as u32).to_be_bytes().to_vec());
+        let e = read_frame::<_, MessageFrame>(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn json_round_trip() {
+        let join = ControlFrame::Request {
+            id: 1,
+            request: RequestEvent {
+                kind: RequestType::JOIN,
+                channel: "room".to_string(),
+                data: None,
+            },
+        };
+        let text = ControlFrame::Message(MessageFrame::new("room".to_string(), b"hi".to_vec()));
+        let binary = ControlFrame::Message(MessageFrame::new("room".to_string(), vec![0xff, 0]));
+
+        let mut buf = Cursor::new(Vec::new());
+        for frame in [&join, &text, &binary] {
+            write_json_frame(&mut buf, frame).await.unwrap();
         }
+        let lines = String::from_utf8(buf.get_ref().clone()).unwrap();
+        assert!(lines.contains(r#""data":"hi""#));
+        assert!(lines.contains(r#""data":{"base64":"/wA="}"#));
+
+        buf.set_position(0);
+        let frames: Vec<ControlFrame> = json_frames(buf).collect().await;
+        assert!(matches!(
+            &frames[..],
+            [
+                ControlFrame::Request { id: 1, request },
+                ControlFrame::Message(text),
+                ControlFrame::Message(binary),
+            ] if matches!(request.kind, RequestType::JOIN)
+                && request.channel == "room"
+                && text.data == b"hi"
+                && binary.data == [0xff, 0]
+        ));
+    }
+
+    #[test]
+    fn subscribe_errors_are_not_publish_errors() {
+        let e = ResponseError::from(gossipsub::SubscriptionError::NotAllowed);
+        assert!(matches!(e, ResponseError::SubscribeFailed(_)));
+        let e = ResponseError::from(gossipsub::SubscriptionError::PublishError(
+            gossipsub::PublishError::AllQueuesFull(1),
+        ));
+        assert!(matches!(e, ResponseError::SubscribeFailed(_)));
     }
 }
@@ -0,0 +1,271 @@
+//! The control socket, a Unix domain socket serving the same requests as the
+//! libp2p socket in [`crate::socket`] without needing an identity or a port.
+//! Every frame is a [`ControlFrame`], written with [`write_frame`] or as a line
+//! of JSON with [`write_json_frame`] depending on the [`Framing`].
+//!
+//! Access control is left to the filesystem. The socket is made so only its
+//! owner can use it, and connections from any other user are dropped.
+//!
+//! For scripts the daemon can also take a single client on stdin and stdout,
+//! which always speaks JSON:
+//!
+//! ```text
+//! {"Request":{"id":1,"request":{"kind":"JOIN","channel":"magic"}}}
+//! {"Request":{"id":2,"request":{"kind":"STRM","channel":""}}}
+//! {"Request":{"id":3,"request":{"kind":"MESG","channel":"magic","data":"hi"}}}
+//! {"Message":{"topic":"magic","data":"hi"}}
+//! {"Message":{"topic":"magic","data":{"base64":"/wA="}}}
+//! ```
+//!
+//! Message data is a string when it is valid UTF-8 and base64 otherwise.
+//!
+//! Closing stdin doesn't detach the client, so answers and streamed messages
+//! keep coming until the daemon is stopped.
+use crate::socket::{
+    CLIENT_QUEUE, ControlFrame, frames, json_frames, write_frame, write_json_frame,
+};
+use clap::{Args, ValueEnum};
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{info, warn};
+
+/// How frames are written on the control socket
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Framing {
+    /// Length prefixed CBOR, like the libp2p message stream
+    #[default]
+    Cbor,
+    /// One JSON object per line
+    Json,
+}
+
+// Where local tools can reach the daemon besides the libp2p socket. Not a doc
+// comment since clap would use it as the about text of every binary that
+// flattens this in.
+#[derive(Args, Clone, Debug, Default)]
+pub struct LocalControl {
+    /// Also serve local clients on a Unix domain socket at this path.
+    /// Only the user running the daemon can use it.
+    #[arg(short, long, value_name = "path")]
+    pub control: Option<PathBuf>,
+    /// Framing used on the control socket.
+    #[arg(long, value_name = "format", default_value = "cbor")]
+    pub control_format: Framing,
+    /// Take requests as JSON lines on stdin and answer on stdout. Logs go to stderr.
+    #[arg(long)]
+    pub stdio: bool,
+}
+
+impl LocalControl {
+    /// Starts whatever was asked for, giving no receiver if that was nothing.
+    /// The socket is removed once the returned [`SocketFile`] is dropped.
+    /// Has to be called from inside a tokio runtime.
+    pub fn start(&self) -> io::Result<(Option<UnboundedReceiver<UnixEvent>>, Option<SocketFile>)> {
+        if self.control.is_none() && !self.stdio {
+            return Ok((None, None));
+        }
+
+        let (events, rx) = mpsc::unbounded_channel();
+        let socket = match &self.control {
+            Some(path) => Some(listen(path, self.control_format, events.clone())?),
+            None => None,
+        };
+        if self.stdio {
+            stdio(events);
+        }
+        Ok((Some(rx), socket))
+    }
+}
+
+/// Unlinks the control socket when dropped
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => info!("Removed control socket {}", self.0.display()),
+            Err(e) => warn!("Could not remove {}: {}", self.0.display(), e),
+        }
+    }
+}
+
+/// What happened on the control socket, every connection gets its own number.
+/// The stdio client is always `0`.
+pub enum UnixEvent {
+    /// Frames sent on the channel are written to the client, which is
+    /// disconnected once every sender is dropped
+    Attached(u64, mpsc::Sender<ControlFrame>),
+    Frame(u64, ControlFrame),
+    Detached(u64),
+}
+
+/// Serves a single client on stdin and stdout
+fn stdio(events: UnboundedSender<UnixEvent>) {
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    let reader = tokio::io::stdin().compat();
+    let writer = tokio::io::stdout().compat_write();
+    tokio::spawn(connection(0, reader, writer, Framing::Json, true, events));
+}
+
+/// Listens on `path`, replacing a socket left behind by a daemon that didn't
+/// shut down cleanly. Anything at `path` that isn't a socket is left alone.
+#[cfg(unix)]
+pub fn listen(
+    path: &Path,
+    framing: Framing,
+    events: UnboundedSender<UnixEvent>,
+) -> io::Result<SocketFile> {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+    use tokio::net::UnixListener;
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is used by another daemon", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let socket = SocketFile(path.to_path_buf());
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    let owner = fs::metadata(path)?.uid();
+    info!("Control socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        let mut next = 0;
+        while !events.is_closed() {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept control client: {}", e);
+                    continue;
+                }
+            };
+            // Someone could have connected before the permissions were set
+            match stream.peer_cred() {
+                Ok(cred) if cred.uid() == owner => {}
+                Ok(cred) => {
+                    warn!("Refused control client running as uid {}", cred.uid());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Refused control client, no credentials: {}", e);
+                    continue;
+                }
+            }
+
+            next += 1;
+            let (reader, writer) = stream.into_split();
+            let reader = reader.compat();
+            let writer = writer.compat_write();
+            let events = events.clone();
+            tokio::spawn(connection(next, reader, writer, framing, false, events));
+        }
+    });
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+pub fn listen(
+    _path: &Path,
+    _framing: Framing,
+    _events: UnboundedSender<UnixEvent>,
+) -> io::Result<SocketFile> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Moves frames between one client and the socket thread until either side
+/// goes away. With `linger` the client stays attached after its input ends.
+async fn connection<R, W>(
+    n: u64,
+    reader: R,
+    mut writer: W,
+    framing: Framing,
+    linger: bool,
+    events: UnboundedSender<UnixEvent>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let mut incoming = match framing {
+        Framing::Cbor => frames::<_, ControlFrame>(reader),
+        Framing::Json => json_frames::<_, ControlFrame>(reader),
+    };
+
+    let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+    if events.send(UnixEvent::Attached(n, tx)).is_err() {
+        return;
+    }
+
+    let mut reading = true;
+    loop {
+        tokio::select! {
+            frame = incoming.next(), if reading => match frame {
+                Some(frame) => {
+                    if events.send(UnixEvent::Frame(n, frame)).is_err() {
+                        break;
+                    }
+                }
+                None if linger => reading = false,
+                None => break,
+            },
+            frame = rx.recv() => {
+                // The socket thread dropped us
+                let Some(frame) = frame else { break };
+                let written = match framing {
+                    Framing::Cbor => write_frame(&mut writer, &frame).await,
+                    Framing::Json => write_json_frame(&mut writer, &frame).await,
+                };
+                if let Err(e) = written {
+                    warn!("Could not write to control client {}: {}", n, e);
+                    break;
+                }
+            }
+        }
+    }
+    let _ = events.send(UnixEvent::Detached(n));
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!("control-{}", rand::random::<u64>()));
+        std::fs::write(&path, "not a socket").unwrap();
+        let (events, _rx) = mpsc::unbounded_channel();
+        let e = listen(&path, Framing::Json, events.clone()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        // A socket left behind by a dead daemon is taken over and removed on drop
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let socket = listen(&path, Framing::Json, events).unwrap();
+        assert!(path.exists());
+        drop(socket);
+        assert!(!path.exists());
+    }
+}